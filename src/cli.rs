@@ -1,7 +1,17 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{builder::BoolishValueParser, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::{ffi::OsString, io, path::PathBuf};
 
 use crate::LinkType;
+use crate::database::RestoreStrategy;
+
+/// Output format of commands that can produce machine-readable output.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
 
 /// CLI for fslinkmanager: manage filesystem links and track them in a local database.
 #[derive(Parser)]
@@ -10,17 +20,236 @@ use crate::LinkType;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    /// Resolve relative source and target arguments against this directory instead of the current one
+    #[arg(long, global = true)]
+    pub resolve_relative_to: Option<PathBuf>,
+    /// No effect; kept for scripts written before a database had to be created with Init
+    #[arg(long, global = true, hide = true)]
+    pub no_create_db: bool,
+    /// Print what Create, Link, Import, Remove, Remove-session, Toggle, Swap, Move, Retarget, Annotate, Prune, Repair,
+    /// Verify --fix, Scan, Compact, Gc --force, Rehash, Restore and Undo would change, prefixed with [dry-run], without changing anything
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// Log what is being done to stderr; repeat for more detail (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+    /// Only log errors
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+    /// Report the results of Create, Remove, Toggle and Status as JSON lines, one object per link, and Count as one object
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Use the database in this directory instead of searching upward from the current one for a `.fslink` folder.
+    /// The search otherwise stops at the first directory containing `.git`
+    #[arg(long, global = true, env = "FSLINK_DIR")]
+    pub database: Option<PathBuf>,
+    /// Only look for a database in the current directory, not in its parents
+    #[arg(long, global = true, env = "FSLINK_NO_PARENT", value_parser = BoolishValueParser::new(), conflicts_with = "database")]
+    pub no_parent: bool,
+    /// Do not ask before deleting or replacing files in Remove, Remove-session, Prune and Create --force.
+    /// Without it, these refuse when stdin is not a terminal
+    #[arg(short, long, global = true)]
+    pub yes: bool,
+    /// Write link records gzip-compressed
+    #[arg(long, global = true)]
+    pub compress: bool,
+}
+
+impl Cli {
+    /// Log level selected by --verbose and --quiet
+    pub fn log_level(&self) -> log::LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => log::LevelFilter::Error,
+            (false, 0) => log::LevelFilter::Warn,
+            (false, 1) => log::LevelFilter::Info,
+            (false, 2) => log::LevelFilter::Debug,
+            (false, _) => log::LevelFilter::Trace,
+        }
+    }
+
+    /// Parse the process arguments, also accepting the link type first: `create <type> <source> <target>`
+    pub fn parse_args() -> Cli {
+        Cli::parse_from(extra_sources_to_options(leading_type_to_end(std::env::args_os().collect())))
+    }
+}
+
+/// Write the completion script for `shell` to stdout. Commands and options are completed, not tracked targets
+pub fn print_completions(shell: Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+}
+
+/// Index of the `create` subcommand, the indices of its positional arguments and whether --target-dir is among its options,
+/// or None if the subcommand is not `create`
+fn create_positionals(args: &[OsString]) -> Option<(usize, Vec<usize>, bool)> {
+    let command = Cli::command();
+    let create = command.find_subcommand("create").unwrap();
+    // Global options are only declared on the top-level command
+    let takes_value = |in_create: bool, arg: &str| {
+        let Some(long) = arg.strip_prefix("--") else { return false };
+        let mut options = command.get_arguments().chain(create.get_arguments().filter(|_| in_create));
+        !long.contains('=') && options.any(|a| a.get_long() == Some(long) && a.get_action().takes_values())
+    };
+
+    let mut positionals = Vec::new();
+    let mut create_index = None;
+    let mut target_dir = false;
+    let mut index = 1;
+    while index < args.len() {
+        let arg = args[index].to_string_lossy();
+        if arg == "--" {
+            positionals.extend(index + 1..args.len());
+            break;
+        } else if arg.starts_with('-') && arg != "-" {
+            target_dir |= create_index.is_some() && (arg == "--target-dir" || arg.starts_with("--target-dir="));
+            if takes_value(create_index.is_some(), &arg) {
+                index += 1;
+            }
+        } else if create_index.is_some() {
+            positionals.push(index);
+        } else if arg == "create" {
+            create_index = Some(index);
+        } else {
+            return None; // Another subcommand
+        }
+        index += 1;
+    }
+    create_index.map(|create| (create, positionals, target_dir))
+}
+
+/// Move a leading link type in `create`'s positional arguments to the end, where clap expects it.
+/// Left alone unless there are exactly three positionals, the first a link type and the last not one, and no --target-dir.
+fn leading_type_to_end(mut args: Vec<OsString>) -> Vec<OsString> {
+    let is_link_type = |arg: &OsString| arg.to_str().is_some_and(|s| LinkType::from_str(s, true).is_ok());
+    if let Some((_, positionals, false)) = create_positionals(&args)
+        && let [first, _, last] = positionals[..]
+        && is_link_type(&args[first]) && !is_link_type(&args[last]) {
+        let link_type = args.remove(first);
+        args.insert(last, link_type);
+    }
+    args
+}
+
+/// Turn the positionals after the first in `create --target-dir` into hidden --source options,
+/// so `create --target-dir <dir> <source>...` links every source into the directory
+fn extra_sources_to_options(mut args: Vec<OsString>) -> Vec<OsString> {
+    let Some((create, positionals, true)) = create_positionals(&args) else {
+        return args;
+    };
+    let Some((_, extra)) = positionals.split_first() else {
+        return args;
+    };
+    let sources: Vec<OsString> = extra.iter().rev().map(|&index| args.remove(index)).collect();
+    for source in sources {
+        let mut option = OsString::from("--source=");
+        option.push(source);
+        args.insert(create + 1, option);
+    }
+    args
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Create a new link
+    /// Create a new database in a directory
+    Init {
+        /// Directory to create the .fslink database in, the current one by default
+        dir: Option<PathBuf>,
+        /// Proceed even if a database already exists there, keeping its records
+        #[arg(long)]
+        force: bool,
+    },
+    /// Create a new link. The link type may also be given first: `create <type> <source> <target>`
     Create {
         /// Source path
-        /// Source file/directory (positional)
+        /// Source file/directory (positional), or a quoted glob pattern to link every match into the target directory
         source: PathBuf,
         /// Target path
         /// Target link path (positional)
+        #[arg(required_unless_present = "target_dir")]
+        target: Option<PathBuf>,
+        /// Link type, Softlink | Hardlink; Softlink if not given
+        #[arg(value_enum)]
+        link_type: Option<LinkType>,
+        /// Link the source into each of these directories, named by the source's file name.
+        /// Further positionals are linked in as well
+        #[arg(long, conflicts_with = "target")]
+        target_dir: Vec<PathBuf>,
+        /// Further sources to link into the --target-dir directories, given as positionals after the first
+        #[arg(long = "source", hide = true, requires = "target_dir")]
+        more_sources: Vec<PathBuf>,
+        /// Link every file inside the source directory individually, mirroring its subdirectories under the target.
+        /// This is how directories are hardlinked; symlinks inside the source are linked as symlinks, not followed.
+        /// The subdirectories it creates are recorded on the links, and removed again with them once empty
+        #[arg(long, requires = "target")]
+        recursive: bool,
+        /// Refuse to create more links than this in one invocation
+        #[arg(long, default_value_t = 10000)]
+        max_records: usize,
+        /// Link type, as an alternative to the positional argument
+        #[arg(long = "link-type", visible_alias = "type", value_enum, conflicts_with = "link_type")]
+        type_flag: Option<LinkType>,
+        /// Store the command-line arguments used to create the link in its record
+        #[arg(long)]
+        record_invocation: bool,
+        /// Warn when the link would shadow a command of the same name later in $PATH
+        #[arg(long)]
+        warn_shadow: bool,
+        /// Treat warnings as errors and skip the affected links
+        #[arg(long)]
+        strict: bool,
+        /// Record the link even if its source does not exist yet, deferring the linking until it does
+        #[arg(long)]
+        allow_missing_source: bool,
+        /// Only create the link on matching machines: an OS name (e.g. linux) or host:<hostname>.
+        /// Repeatable; conditions of the same kind are alternatives, different kinds must all match
+        #[arg(long)]
+        only_on: Vec<String>,
+        /// Point softlinks to the source by a path relative to the link, so moving both together keeps them working
+        #[arg(long)]
+        relative: bool,
+        /// Move an existing file or directory at the target to <target>.bak (or .bak.N) instead of refusing it
+        #[arg(long)]
+        backup: bool,
+        /// Replace an existing file, or a symlink to another source, at the target after confirmation (see --yes).
+        /// Directories are left alone; combine with --backup to move the old target aside instead of deleting it
+        #[arg(long)]
+        force: bool,
+        /// Use the target as the link path even if it is a directory, instead of linking inside it
+        #[arg(short = 'T', long, conflicts_with = "recursive")]
+        no_target_directory: bool,
+        /// Name the link, to address it with --name later instead of its target path. Must be unique
+        #[arg(long)]
+        name: Option<String>,
+        /// Give softlinks their source's access and modification times, and with --recursive give the
+        /// directories created for the mirror their source directory's permissions
+        #[arg(long)]
+        preserve: bool,
+        /// When one link of a batch (--target-dir, --recursive or a glob) fails, keep the links created before it and go on
+        /// with the rest, instead of rolling them all back
+        #[arg(long)]
+        keep_partial: bool,
+        /// Record a note on why the link exists, shown by List, Status and Info
+        #[arg(long)]
+        note: Option<String>,
+        /// Create a softlink instead when a hardlink is impossible because source and target are on different filesystems.
+        /// The link is recorded as the softlink it is
+        #[arg(long)]
+        fallback_symlink: bool,
+        /// Tag the created links, to address them as a group later. Repeatable
+        #[arg(long)]
+        tag: Vec<String>,
+        /// Converge the target to the requested link: keep it if already correct,
+        /// retarget a tracked link with another source, back up anything else in the way
+        #[arg(long)]
+        replace_if_different: bool,
+    },
+    /// Ensure a link exists: create it if the target is absent, accept an existing identical link.
+    /// A tracked link that is toggled off or missing is linked again; one tracked with another link type is a conflict
+    Link {
+        /// Source file/directory (positional)
+        source: PathBuf,
+        /// Target link path (positional)
         target: PathBuf,
         /// Link type, Softlink | Hardlink
         #[arg(value_enum)]
@@ -30,15 +259,263 @@ pub enum Commands {
     Remove {
         /// Target path
         /// Target link path (positional)
-        target: PathBuf,
+        #[arg(required_unless_present_any = ["by_id", "name", "source", "tag"])]
+        target: Option<PathBuf>,
+        /// Address the link by its record id instead of its target path
+        #[arg(long, conflicts_with = "target")]
+        by_id: Option<String>,
+        /// Address the link by the name given with Create --name instead of its target path
+        #[arg(long, conflicts_with_all = ["target", "by_id"])]
+        name: Option<String>,
+        /// Remove every link of this source and delete their records, instead of a single link
+        #[arg(long, conflicts_with_all = ["target", "by_id", "name"])]
+        source: Option<PathBuf>,
+        /// Remove every link with this tag and delete their records, instead of a single link
+        #[arg(long, conflicts_with_all = ["target", "by_id", "name", "source"])]
+        tag: Option<String>,
+        /// Move the file backed up by Create --backup back to the target
+        #[arg(long)]
+        restore_backup: bool,
+        /// Delete the target even if it no longer is the tracked link, e.g. a file that has replaced it
+        #[arg(long)]
+        force: bool,
+        /// No effect: an untracked target always exits with a non-zero code now. Kept for existing scripts
+        #[arg(long, hide = true)]
+        fail_if_untracked: bool,
     },
     /// List all tracked links
-    List,
+    List {
+        /// Print only target paths, separated by NUL bytes (for `xargs -0`)
+        #[arg(long, visible_alias = "null")]
+        print0: bool,
+        /// Do not print the summary of broken links after the list
+        #[arg(long)]
+        no_footer: bool,
+        /// Show paths relative to this directory (the database root if no value is given); paths outside it stay absolute
+        #[arg(long, value_name = "DIR")]
+        relative_to: Option<Option<PathBuf>>,
+        /// Only list links created in this session (see Info)
+        #[arg(long)]
+        session: Option<String>,
+        /// Only list links with this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Only list links of this type
+        #[arg(long = "type", value_enum, value_name = "TYPE")]
+        link_type: Option<LinkType>,
+        /// Only list links recorded as present in the filesystem
+        #[arg(long, conflicts_with = "missing")]
+        existing: bool,
+        /// Only list links recorded as not present in the filesystem
+        #[arg(long)]
+        missing: bool,
+        /// Only list links whose source or target lies inside this directory
+        #[arg(long, value_name = "DIR")]
+        under: Option<PathBuf>,
+        /// Print each link with a template, e.g. "{source} => {target} [{type}] {state}".
+        /// Placeholders: {source}, {target}, {type}, {exists}, {state}, {id}, {session}, {created}, {updated} (unix seconds), {tags}, {note};
+        /// {{ and }} are literal braces
+        #[arg(long, conflicts_with = "print0")]
+        template: Option<String>,
+        /// Output format; json prints every link as an object in one array
+        #[arg(long, value_enum, default_value_t, conflicts_with_all = ["print0", "template"])]
+        format: OutputFormat,
+    },
+    /// Print a completion script for a shell, e.g. `fslinkmanager completions bash > /etc/bash_completion.d/fslinkmanager`
+    #[command(hide = true)]
+    Completions {
+        /// Shell to complete in (positional)
+        shell: Shell,
+    },
+    /// Print tracked target paths starting with a prefix, for shell completion
+    Complete {
+        /// Target path prefix, made absolute like other target arguments
+        prefix: Option<PathBuf>,
+    },
+    /// List tracked links whose source is the given path or lies under it
+    /// Exits with 1 if there are none
+    Dependents {
+        /// Source file or directory
+        source: PathBuf,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Show the full record of a tracked link
+    Info {
+        /// Target link path
+        /// Target link path (positional)
+        #[arg(required_unless_present = "by_id")]
+        target: Option<PathBuf>,
+        /// Address the link by its record id instead of its target path
+        #[arg(long, conflicts_with = "target")]
+        by_id: Option<String>,
+        /// Show paths relative to this directory (the database root if no value is given); paths outside it stay absolute
+        #[arg(long, value_name = "DIR")]
+        relative_to: Option<Option<PathBuf>>,
+    },
     /// Toggle (enable/disable) a link
     Toggle {
         /// Target link path
+        /// Target link path (positional)
+        #[arg(required_unless_present_any = ["by_id", "name", "all", "tag"])]
+        target: Option<PathBuf>,
+        /// Address the link by its record id instead of its target path
+        #[arg(long, conflicts_with = "target")]
+        by_id: Option<String>,
+        /// Address the link by the name given with Create --name instead of its target path
+        #[arg(long, conflicts_with_all = ["target", "by_id"])]
+        name: Option<String>,
+        /// Toggle every tracked link, continuing past links that fail
+        #[arg(long, conflicts_with_all = ["target", "by_id", "name"])]
+        all: bool,
+        /// Toggle every link with this tag, like --all
+        #[arg(long, conflicts_with_all = ["target", "by_id", "name", "all"])]
+        tag: Option<String>,
+        /// Link the links that are not linked, leaving linked ones alone
+        #[arg(long, conflicts_with = "off")]
+        on: bool,
+        /// Unlink the links that are linked, leaving unlinked ones alone
+        #[arg(long)]
+        off: bool,
+        /// No effect: an untracked target always exits with a non-zero code now. Kept for existing scripts
+        #[arg(long, hide = true)]
+        fail_if_untracked: bool,
+    },
+    /// Back up every link record to a single JSON lines file, e.g. to carry the links to another machine
+    #[command(visible_alias = "export")]
+    Backup {
+        /// Backup file to write
+        file: PathBuf,
+    },
+    /// Restore link records from a Backup file, without touching the linked files unless --apply is given
+    Restore {
+        /// Backup file to read
+        file: PathBuf,
+        /// How to treat records that already exist in the database
+        #[arg(long, value_enum, default_value_t)]
+        strategy: RestoreStrategy,
+        /// Also create the restored links that are recorded as present, like Repair
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Revert the last Create, Remove, Toggle or Move, as recorded in the journal
+    Undo,
+    /// Rewrite every record in the current format, compressing them with --compress
+    Compact,
+    /// Check the database itself: that records can be saved, that every file is a valid record reachable under its id,
+    /// and that no two records share a target. Prints each problem with a suggested fix, exiting with 1 if there are any
+    Doctor,
+    /// Save every record again under the id its normalized paths hash to, after a change to path normalization or hashing
+    /// left records where lookups miss them. Records that turn out to be the same link are merged
+    Rehash,
+    /// Report files in the database that are not valid, reachable records, and delete them with --force
+    Gc {
+        /// Delete the reported files
+        #[arg(long)]
+        force: bool,
+    },
+    /// Check that the filesystem matches the database, exiting with 1 and a report of every discrepancy if not.
+    /// Links restricted to other machines with --only-on are skipped
+    Check {
+        /// Also follow each softlink's symlink chain and report loops and chains that never reach a real file
+        #[arg(long)]
+        check_loops: bool,
+    },
+    /// Print the state of every tracked link (OK, MISSING, DANGLING, HIJACKED, ...), exiting with 1 if any is not OK.
+    /// Links restricted to other machines with --only-on are skipped
+    Status,
+    /// Print how many links are tracked, by type, how many are present on disk and how many have lost their source
+    Count,
+    /// Move a link to a new target path, keeping its source: `move <target> <new-target>` or `move --name <name> <new-target>`
+    Move {
+        /// Current target link path (positional), or the new one with --name
+        target: PathBuf,
+        /// New target link path (positional), which must not exist yet
+        #[arg(required_unless_present = "name", conflicts_with = "name")]
+        new_target: Option<PathBuf>,
+        /// Address the link by the name given with Create --name instead of its target path
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Point a link to a new source, for when its source file has moved
+    Retarget {
+        /// Target link path (positional)
+        target: PathBuf,
+        /// New source path (positional), which must exist
+        new_source: PathBuf,
+    },
+    /// Print where a link ultimately points: each tracked link from the target to its source, following sources that are
+    /// themselves tracked targets, then the real file. Exits with 1 if the chain is broken or loops
+    Resolve {
+        /// Target link path (positional)
+        target: PathBuf,
+    },
+    /// Set the note of a link, as given by Create --note. An empty note removes it
+    Annotate {
         /// Target link path (positional)
         target: PathBuf,
+        /// Note text (positional)
+        note: String,
+    },
+    /// Start tracking an existing symlink, taking its source from where it points
+    Import {
+        /// Symlink path (positional)
+        target: PathBuf,
+    },
+    /// Import every untracked symlink found under a directory
+    Scan {
+        /// Directory to walk (positional)
+        dir: PathBuf,
+        /// Also report symlinks whose source no longer exists
+        #[arg(long)]
+        dangling: bool,
+    },
+    /// Recreate links that are recorded as present but missing on disk, e.g. after cloning a checked-in database,
+    /// and create deferred links whose source now exists. Links restricted to other machines with --only-on are skipped
+    Repair,
+    /// Verify that every present link still is the tracked link: softlinks point to their source, hardlinks share its inode.
+    /// Exits with 1 if any does not
+    Verify {
+        /// Relink mismatching links whose target is missing or a symlink; other files are never replaced
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Swap a link's roles: move the source file to the target location and link it back from the old source location
+    Swap {
+        /// Target link path
+        /// Target link path (positional)
+        target: PathBuf,
+    },
+    /// Answer line-delimited JSON requests (list, which, dependents) on a Unix domain socket
+    #[cfg(unix)]
+    Serve {
+        /// Socket path to listen on
+        #[arg(long)]
+        socket: PathBuf,
+    },
+    /// Watch the sources and targets of the tracked links and repair links broken by changes to them, until interrupted:
+    /// missing targets are relinked, and hardlinks whose source was replaced (e.g. by an editor's save) are recreated
+    Watch,
+    /// Compare a link's record field by field against the live filesystem
+    Inspect {
+        /// Target link path
+        /// Target link path (positional)
+        #[arg(required_unless_present = "by_id")]
+        target: Option<PathBuf>,
+        /// Address the link by its record id instead of its target path
+        #[arg(long, conflicts_with = "target")]
+        by_id: Option<String>,
+        /// Output format
+        #[arg(long, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Drop the records of links whose source no longer exists, removing their dangling softlinks.
+    /// Deferred links are kept, since their source is expected to appear later
+    Prune,
+    /// Remove every link created in one session, deleting the links and their records
+    RemoveSession {
+        /// Session id, as shown by Info
+        session: String,
     },
 }
-
@@ -1,117 +1,959 @@
     
-use core::panic;
-use std::{fs::{create_dir, File, OpenOptions}, io::{BufReader, BufWriter, Write}, path::{Path, PathBuf}};
+use std::{cell::{Cell, Ref, RefCell}, collections::HashMap, fmt::{self, Display, Formatter}, fs::{create_dir, remove_file, File, OpenOptions}, io::{self, BufRead, BufReader, BufWriter, Write}, path::{Path, PathBuf}};
 use blake2::{Blake2b512, Digest};
+use clap::ValueEnum;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::{debug, info, warn};
 
 
-use crate::QuickLink;
+use serde::{Deserialize, Serialize};
+
+use crate::{absolute_path, normalize_path, LinkType, QuickLink};
+
+/// Errors opening a link database
+pub enum StorageError {
+    /// No `.fslink` folder in the searched directory or any of its parents
+    NoDatabaseFound,
+    /// Format: database folder
+    DatabaseExists(PathBuf),
+    /// Format: path that could not be accessed
+    PermissionDenied(PathBuf),
+    /// Format: database schema version, newest version this build supports
+    UnsupportedVersion(u32, u32),
+    /// Format: io_error
+    Io(io::Error),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StorageError::NoDatabaseFound => write!(f, "No .fslink database found; run `init` first"),
+            StorageError::DatabaseExists(folder_path) => write!(f, "A database already exists at {}", folder_path.display()),
+            StorageError::PermissionDenied(path) => write!(f, "Permission denied while opening the database at {}", path.display()),
+            StorageError::UnsupportedVersion(version, supported) => write!(f, "The database has schema version {}, but this version of fslinkmanager only supports up to {}; please upgrade", version, supported),
+            StorageError::Io(ioerror) => write!(f, "Encountered an io error while opening the database: {}", ioerror),
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(value: io::Error) -> Self {
+        StorageError::Io(value)
+    }
+}
+
+impl StorageError {
+    /// An io error that happened while accessing `path`, singling out missing permissions
+    fn at(path: &Path, error: io::Error) -> StorageError {
+        match error.kind() {
+            io::ErrorKind::PermissionDenied => StorageError::PermissionDenied(path.to_path_buf()),
+            _ => StorageError::Io(error),
+        }
+    }
+}
+
+/// Schema version written to new databases. Older databases are migrated when opened.
+/// 1: no version file, 2: version file added
+const SCHEMA_VERSION: u32 = 2;
+
+/// File extension of gzip-compressed records
+const COMPRESSED_EXTENSION: &str = "gz";
+
+/// How Restore treats backed up records that are already present in the database
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum RestoreStrategy {
+    /// Replace the existing record with the backed up one
+    Overwrite,
+    /// Keep the existing record
+    #[default]
+    Skip,
+    /// Abort the restore without writing anything
+    Fail,
+}
+
+/// Why a file in the links folder is an orphan that no lookup can reach
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum OrphanReason {
+    /// The file is empty, e.g. left by a save that was interrupted
+    Empty,
+    /// The file cannot be read as a record
+    Invalid,
+    /// A valid record, saved under another id than the one its link is looked up by
+    Unreachable,
+}
+
+impl Display for OrphanReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            OrphanReason::Empty => write!(f, "empty file"),
+            OrphanReason::Invalid => write!(f, "not a valid record"),
+            OrphanReason::Unreachable => write!(f, "not reachable under its record id"),
+        }
+    }
+}
+
+/// Serde format of paths in records: a string, or an array of bytes for paths that are not valid UTF-8
+pub mod path_bytes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    impl From<Stored> for PathBuf {
+        fn from(stored: Stored) -> PathBuf {
+            match stored {
+                Stored::Text(text) => PathBuf::from(text),
+                Stored::Bytes(bytes) => crate::platform::path_from_bytes(bytes),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => serializer.serialize_str(text),
+            None => path.as_os_str().as_encoded_bytes().serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(Stored::deserialize(deserializer)?.into())
+    }
+
+    /// The same format for optional paths
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => super::serialize(path, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PathBuf>, D::Error> {
+            Ok(Option::<Stored>::deserialize(deserializer)?.map(PathBuf::from))
+        }
+    }
+
+    /// The same format for lists of paths
+    pub mod vec {
+        use super::*;
+        use serde::ser::SerializeSeq;
+
+        struct Element<'a>(&'a Path);
+
+        impl Serialize for Element<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(self.0, serializer)
+            }
+        }
+
+        pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(paths.len()))?;
+            for path in paths {
+                seq.serialize_element(&Element(path))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+            Ok(Vec::<Stored>::deserialize(deserializer)?.into_iter().map(PathBuf::from).collect())
+        }
+    }
+}
+
+/// The inverse of a mutating operation, which Undo applies. Links are identified by source and target.
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(tag = "undo", rename_all = "snake_case")]
+pub enum JournalEntry {
+    /// Inverse of Create: unlink the link and forget it
+    Unlink {
+        #[serde(with = "path_bytes")] source: PathBuf,
+        #[serde(with = "path_bytes")] target: PathBuf,
+    },
+    /// Inverse of Remove: link it again and record it as present
+    Relink {
+        #[serde(with = "path_bytes")] source: PathBuf,
+        #[serde(with = "path_bytes")] target: PathBuf,
+        /// The removed record, saved again by Undo, when Remove --source or --tag deleted it
+        #[serde(default, skip_serializing_if = "Option::is_none")] record: Option<Box<QuickLink>>,
+    },
+    /// Inverse of Toggle: toggle it back
+    Toggle {
+        #[serde(with = "path_bytes")] source: PathBuf,
+        #[serde(with = "path_bytes")] target: PathBuf,
+    },
+    /// Inverse of Move: move the link at `target` back to `to`
+    Move {
+        #[serde(with = "path_bytes")] source: PathBuf,
+        #[serde(with = "path_bytes")] target: PathBuf,
+        #[serde(with = "path_bytes")] to: PathBuf,
+    },
+}
+
+impl Display for JournalEntry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            JournalEntry::Unlink { source, target } => write!(f, "unlink {} -> {}", source.display(), target.display()),
+            JournalEntry::Relink { source, target, .. } => write!(f, "relink {} -> {}", source.display(), target.display()),
+            JournalEntry::Toggle { source, target } => write!(f, "toggle {} -> {}", source.display(), target.display()),
+            JournalEntry::Move { source, target, to } => write!(f, "move {} -> {} back to {}", source.display(), target.display(), to.display()),
+        }
+    }
+}
+
+/// Log of JournalEntry records in `.fslink/journal`, one JSON object per line, newest last
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// Append an entry
+    pub fn push(&self, entry: &JournalEntry) -> io::Result<()> {
+        let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)
+    }
+
+    /// The newest entry, if any
+    pub fn last(&self) -> io::Result<Option<JournalEntry>> {
+        Ok(self.read()?.pop())
+    }
+
+    /// Remove and return the newest entry, if any
+    pub fn pop(&self) -> io::Result<Option<JournalEntry>> {
+        let mut entries = self.read()?;
+        let last = entries.pop();
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        for entry in &entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(last)
+    }
+
+    fn read(&self) -> io::Result<Vec<JournalEntry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(entries)
+    }
+}
 
 pub struct LinkStorage {
     folder_path: PathBuf,
-    link_folder: PathBuf
+    link_folder: PathBuf,
+    /// Whether records are written gzip-compressed
+    compress: bool,
+    /// Every record by id, read from the links folder on first use and kept up to date by writes
+    cache: RefCell<Option<HashMap<String, QuickLink>>>,
+    /// Source of every tracked softlink by target, built from the cached records on first use and kept up to date by writes
+    softlinks: RefCell<Option<HashMap<PathBuf, PathBuf>>>,
+    /// The sorted (target, id) pairs of `targets.idx`, read on first use and kept up to date by writes
+    index: RefCell<Option<Vec<(String, String)>>>,
+    /// Whether a batch is running, during which changes to the index are written only once it ends
+    batching: Cell<bool>,
+    /// Whether the index changed during the running batch, so `targets.idx` was removed and is written when it ends
+    index_stale: Cell<bool>,
 }
 
 impl LinkStorage {
-    pub fn new(initial_path: &PathBuf) -> LinkStorage {
-        let folder_path: PathBuf;
-        let mut current_searched_path = initial_path.canonicalize().unwrap(); // Make the path absolute
-        'search: loop {
-            for e in current_searched_path.read_dir().expect("Failed to read initial search directory") {
-                let entry = e.unwrap();
-                if entry.file_name() == ".fslink" {
-                    folder_path = entry.path();
-                    break 'search;
-                }
+    /// Open the database in the closest `.fslink` folder, searching upward from `initial_path`.
+    /// The search stops at a repository root (a directory containing `.git`), so a database outside the repository is not picked up.
+    pub fn new(initial_path: &Path) -> Result<LinkStorage, StorageError> {
+        let mut current_searched_path = initial_path.canonicalize().map_err(|e| StorageError::at(initial_path, e))?; // Make the path absolute
+        let folder_path = loop {
+            let candidate = current_searched_path.join(".fslink");
+            debug!("Searching for a database at {}", candidate.display());
+            match candidate.metadata() {
+                Ok(metadata) if metadata.is_dir() => break candidate,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Err(StorageError::PermissionDenied(candidate)),
+                _ => {}
             }
-            if current_searched_path.as_os_str() == "/" { // Search reached the root directory
-                panic!("No error handling in linkstorage yet! - search reached the root directory");
-                break 'search;
+            if current_searched_path.join(".git").exists() {
+                debug!("Stopping the search at repository root {}", current_searched_path.display());
+                return Err(StorageError::NoDatabaseFound);
             }
-            current_searched_path = current_searched_path.parent().unwrap().to_path_buf();
+            match current_searched_path.parent() {
+                Some(parent) => current_searched_path = parent.to_path_buf(),
+                None => return Err(StorageError::NoDatabaseFound), // Search reached the root directory
+            }
+        };
+        LinkStorage::open_folder(folder_path)
+    }
+
+    /// Open the database in the `.fslink` folder of `directory`, without searching its parents
+    pub fn open(directory: &Path) -> Result<LinkStorage, StorageError> {
+        let folder_path = directory.join(".fslink");
+        debug!("Opening the database at {}", folder_path.display());
+        match folder_path.metadata() {
+            Ok(metadata) if metadata.is_dir() => LinkStorage::open_folder(folder_path),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(StorageError::PermissionDenied(folder_path)),
+            _ => Err(StorageError::NoDatabaseFound),
         }
+    }
+
+    fn open_folder(folder_path: PathBuf) -> Result<LinkStorage, StorageError> {
         let link_folder = folder_path.join("links");
-        if !dir_contains(&folder_path, "links") {
-            create_dir(folder_path.join("links")).unwrap();
+        if !link_folder.is_dir() {
+            create_dir(&link_folder).map_err(|e| StorageError::at(&link_folder, e))?;
         }
-        
-        //println!("{} {}", folder_path.display(), link_folder.display());
-        LinkStorage { folder_path, link_folder }
+        debug!("Using database {}", folder_path.display());
+        let storage = LinkStorage { folder_path, link_folder, compress: false, cache: RefCell::new(None), softlinks: RefCell::new(None),
+            index: RefCell::new(None), batching: Cell::new(false), index_stale: Cell::new(false) };
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    /// Bring an older database up to SCHEMA_VERSION, refusing databases newer than it
+    fn migrate(&self) -> Result<(), StorageError> {
+        let version_path = self.folder_path.join("version");
+        let version = match std::fs::read_to_string(&version_path) {
+            Ok(content) => content.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("invalid version file {}", version_path.display())))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(StorageError::at(&version_path, e)),
+        };
+        if version > SCHEMA_VERSION {
+            return Err(StorageError::UnsupportedVersion(version, SCHEMA_VERSION));
+        }
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        info!("Migrating database from schema version {} to {}", version, SCHEMA_VERSION);
+        if version < 2 {
+            // Records without newer fields read fine through their serde defaults, only the index may be stale
+            self.reindex()?;
+        }
+        std::fs::write(&version_path, format!("{}\n", SCHEMA_VERSION)).map_err(|e| StorageError::at(&version_path, e))?;
+        Ok(())
+    }
+
+    /// The directory containing the `.fslink` folder
+    pub fn root(&self) -> &Path {
+        self.folder_path.parent().unwrap()
+    }
+
+    /// The journal of operations Undo can revert
+    pub fn journal(&self) -> Journal {
+        Journal { path: self.folder_path.join("journal") }
+    }
+
+    /// Write records gzip-compressed from now on. Records in either format stay readable.
+    pub fn compressed(mut self, compress: bool) -> LinkStorage {
+        self.compress = compress;
+        self
     }
 
     /// Get a QuickLink by its source and target path (using hash as filename)
-    pub fn get_quicklink(&self, source: &str, target: &str) -> Option<QuickLink> {
+    pub fn get_quicklink(&self, source: &Path, target: &Path) -> Option<QuickLink> {
+        self.find_slot(source, target).1
+    }
+
+    /// The record id of a QuickLink, under which it is saved or would be saved
+    pub fn record_id(&self, link: &QuickLink) -> String {
+        self.find_slot(&link.source, &link.target).0
+    }
+
+    /// The id the record for `source` and `target` is saved under, along with the record.
+    /// If there is no such record, the first free id among the hash and its collision suffixes.
+    fn find_slot(&self, source: &Path, target: &Path) -> (String, Option<QuickLink>) {
         let hash = hash_source_target(source, target);
-        let file_path = self.link_folder.join(hash);
-        if file_path.exists() {
-            let target_file_reader = BufReader::new(File::open(file_path).unwrap());
-            let resolved_link: QuickLink = serde_json::from_reader(target_file_reader).unwrap();
-            return Some(resolved_link);
+        debug!("Computed hash {} for {} -> {}", hash, source.display(), target.display());
+        let records = self.records();
+        collision_ids(&hash).find_map(|id| {
+            match records.get(&id) {
+                None => Some((id, None)),
+                Some(link) if link.source == source && link.target == target => Some((id, Some(link.clone()))),
+                Some(_) => {
+                    debug!("Record {} belongs to another link with the same hash", id);
+                    None
+                },
+            }
+        }).expect("collision ids are unbounded")
+    }
+
+    /// Get a QuickLink by its record id (the hash its file is named by)
+    pub fn get_by_id(&self, id: &str) -> Option<QuickLink> {
+        if !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return None;
         }
-        None
+        self.records().get(id).cloned()
     }
 
-    /// Get a QuickLink by its target path. Automatically makes the path absolute.
+    /// Get a QuickLink by its target path. Makes the path absolute and normalized, like QuickLink::new does.
     pub fn find_by_target(&self, target: &Path) -> Option<QuickLink> {
-        let abs_target = if target.is_absolute() {
-            target.to_path_buf()
+        let abs_target = absolute_path(target, &std::env::current_dir().unwrap());
+        self.records().values().find(|l| l.target == abs_target).cloned()
+    }
+
+    /// Get all QuickLinks of the given source path. Makes the path absolute and normalized, like QuickLink::new does.
+    pub fn find_all_by_source(&self, source: &Path) -> Vec<QuickLink> {
+        let abs_source = absolute_path(source, &std::env::current_dir().unwrap());
+        self.get_all().into_iter().filter(|l| l.source == abs_source).collect()
+    }
+
+    /// Get all QuickLinks tagged with `tag`
+    pub fn find_by_tag(&self, tag: &str) -> Vec<QuickLink> {
+        self.get_all().into_iter().filter(|l| l.tags.iter().any(|t| t == tag)).collect()
+    }
+
+    /// Get the QuickLink named `name`. Fails if more than one link has that name.
+    pub fn find_by_name(&self, name: &str) -> io::Result<Option<QuickLink>> {
+        let mut named: Vec<QuickLink> = self.get_all().into_iter().filter(|l| l.name.as_deref() == Some(name)).collect();
+        if named.len() > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} links are named {}", named.len(), name)));
+        }
+        Ok(named.pop())
+    }
+
+    /// Get all QuickLinks whose source is the given absolute path or lies inside it
+    pub fn find_by_source_prefix(&self, source: &Path) -> Vec<QuickLink> {
+        self.get_all().into_iter().filter(|l| l.source.starts_with(source)).collect()
+    }
+
+    /// Get all QuickLinks whose target path starts with `prefix`, using the sorted target index
+    pub fn find_by_target_prefix(&self, prefix: &str) -> Vec<QuickLink> {
+        let index = self.load_index();
+        let records = self.records();
+        let start = index.partition_point(|(target, _)| target.as_str() < prefix);
+        index[start..].iter()
+            .take_while(|(target, _)| target.starts_with(prefix))
+            .filter_map(|(_, hash)| records.get(hash).cloned())
+            .collect()
+    }
+
+    /// Get all saved QuickLinks as a Vec, sorted by target and then source so listings are stable
+    pub fn get_all(&self) -> Vec<QuickLink> {
+        let mut links: Vec<QuickLink> = self.records().values().cloned().collect();
+        links.sort_by(|a, b| a.target.cmp(&b.target).then_with(|| a.source.cmp(&b.source)));
+        links
+    }
+
+    /// Every saved QuickLink with the id its record file is named by, sorted by id
+    pub fn get_all_with_ids(&self) -> Vec<(String, QuickLink)> {
+        let mut records: Vec<(String, QuickLink)> = self.records().iter().map(|(id, link)| (id.clone(), link.clone())).collect();
+        records.sort_by(|a, b| a.0.cmp(&b.0));
+        records
+    }
+
+    /// The cached records by id, reading them from the links folder if they have not been yet.
+    /// Of a record stored in both formats, the plain one is used, like `record_path` does.
+    fn records(&self) -> Ref<'_, HashMap<String, QuickLink>> {
+        if self.cache.borrow().is_none() {
+            let mut records = HashMap::new();
+            if let Ok(entries) = self.link_folder.read_dir() {
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    let compressed = match path.extension() {
+                        None => false,
+                        Some(extension) if extension == COMPRESSED_EXTENSION => true,
+                        Some(_) => continue, // e.g. a temporary file left by an interrupted save
+                    };
+                    if let (Some(link), Some(id)) = (read_record(&path), path.file_stem()) {
+                        let id = id.to_string_lossy().into_owned();
+                        if !compressed || !records.contains_key(&id) {
+                            records.insert(id, link);
+                        }
+                    }
+                }
+            }
+            debug!("Loaded {} records", records.len());
+            *self.cache.borrow_mut() = Some(records);
+        }
+        Ref::map(self.cache.borrow(), |cache| cache.as_ref().unwrap())
+    }
+
+    /// The source of every tracked softlink by its target, for following links without going through every record
+    pub fn softlinks(&self) -> Ref<'_, HashMap<PathBuf, PathBuf>> {
+        if self.softlinks.borrow().is_none() {
+            let softlinks = self.records().values()
+                .filter(|link| link.linktype == LinkType::Softlink)
+                .map(|link| (link.target.clone(), link.source.clone()))
+                .collect();
+            *self.softlinks.borrow_mut() = Some(softlinks);
+        }
+        Ref::map(self.softlinks.borrow(), |softlinks| softlinks.as_ref().unwrap())
+    }
+
+    /// Drop the cached records, so they are read again from the links folder, e.g. after another process changed them
+    pub fn refresh(&self) {
+        *self.cache.borrow_mut() = None;
+        *self.softlinks.borrow_mut() = None;
+        *self.index.borrow_mut() = None;
+    }
+
+    /// Run `batch`, writing the target index once when it ends instead of on every saved or removed record.
+    /// Meanwhile `targets.idx` is removed, so a batch that is interrupted leaves it to be rebuilt from the records.
+    pub fn batch<T>(&self, batch: impl FnOnce() -> T) -> io::Result<T> {
+        self.batching.set(true);
+        let result = batch();
+        self.batching.set(false);
+        if self.index_stale.replace(false) {
+            self.write_index(&self.load_index())?;
+        }
+        Ok(result)
+    }
+
+    /// Save a QuickLink to a file named by a hash of its source and target path.
+    /// The record is written to a temporary file first and renamed over the old one, so an interrupted save leaves it intact.
+    pub fn save_quicklink(&self, link: &QuickLink) -> io::Result<()> {
+        let (hash, _) = self.find_slot(&link.source, &link.target);
+        self.save_at(hash, link)
+    }
+
+    /// Write the record of a QuickLink under `hash`, whichever id its paths hash to
+    fn save_at(&self, hash: String, link: &QuickLink) -> io::Result<()> {
+        let target_str = link.target.to_string_lossy();
+        let plain_path = self.link_folder.join(&hash);
+        let compressed_path = self.link_folder.join(format!("{}.{}", hash, COMPRESSED_EXTENSION));
+        let (record_path, stale_path) = if self.compress { (compressed_path, plain_path) } else { (plain_path, compressed_path) };
+        let mut temp_name = record_path.file_name().unwrap().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = self.link_folder.join(temp_name);
+        let temp_file = OpenOptions::new().write(true).truncate(true).create(true).open(&temp_path)?;
+        let mut writer = BufWriter::new(temp_file);
+        if self.compress {
+            let mut encoder = GzEncoder::new(&mut writer, Compression::default());
+            serde_json::to_writer(&mut encoder, link)?;
+            encoder.finish()?;
         } else {
-            std::env::current_dir().unwrap().join(target)
+            serde_json::to_writer(&mut writer, link)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        std::fs::rename(&temp_path, &record_path)?;
+        debug!("Wrote record to {}", record_path.display());
+        if stale_path.exists() {
+            remove_file(stale_path)?;
+        }
+        if let Some(records) = self.cache.borrow_mut().as_mut() {
+            records.insert(hash.clone(), link.clone());
+        }
+        if let Some(softlinks) = self.softlinks.borrow_mut().as_mut() {
+            if link.linktype == LinkType::Softlink {
+                softlinks.insert(link.target.clone(), link.source.clone());
+            } else if softlinks.get(&link.target) == Some(&link.source) {
+                softlinks.remove(&link.target);
+            }
+        }
+
+        self.update_index(|index| match index.binary_search(&(target_str.to_string(), hash.clone())) {
+            Ok(_) => false,
+            Err(position) => {
+                index.insert(position, (target_str.into_owned(), hash));
+                true
+            },
+        })
+    }
+
+    /// Delete the record of a QuickLink, leaving the filesystem untouched
+    pub fn remove_quicklink(&self, link: &QuickLink) -> io::Result<()> {
+        let target_str = link.target.to_string_lossy();
+        let (id, found) = self.find_slot(&link.source, &link.target);
+        let record_path = self.record_path(&id).filter(|_| found.is_some())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no record saved for this link"))?;
+        remove_file(&record_path)?;
+        debug!("Removed record {}", record_path.display());
+        if let Some(records) = self.cache.borrow_mut().as_mut() {
+            records.remove(&id);
+        }
+        // Another record may track a softlink at the same target, so the map is rebuilt on next use
+        *self.softlinks.borrow_mut() = None;
+        // Lookups stop at the first free id, so move the last colliding record into the gap
+        let hash = hash_source_target(&link.source, &link.target);
+        let last = collision_ids(&hash).skip_while(|candidate| *candidate != id).skip(1)
+            .map_while(|candidate| self.record_path(&candidate).map(|path| (candidate, path)))
+            .last();
+        if let Some((last_id, last_path)) = &last {
+            let moved_path = if last_path.extension().is_some_and(|extension| extension == COMPRESSED_EXTENSION) {
+                self.link_folder.join(format!("{}.{}", id, COMPRESSED_EXTENSION))
+            } else {
+                self.link_folder.join(&id)
+            };
+            std::fs::rename(last_path, moved_path)?;
+            if let Some(records) = self.cache.borrow_mut().as_mut()
+                && let Some(moved) = records.remove(last_id) {
+                records.insert(id.clone(), moved);
+            }
+        }
+        self.update_index(|index| {
+            index.retain(|entry| *entry != (target_str.to_string(), id.clone()));
+            if let Some((last_id, _)) = &last {
+                for entry in index.iter_mut().filter(|(_, entry_id)| entry_id == last_id) {
+                    entry.1 = id.clone();
+                }
+                index.sort();
+            }
+            true
+        })
+    }
+
+    /// Rewrite every record in the current storage format, returning how many were rewritten
+    pub fn compact(&self) -> io::Result<usize> {
+        let links = self.get_all();
+        self.batch(|| links.iter().try_for_each(|link| self.save_quicklink(link)))??;
+        Ok(links.len())
+    }
+
+    /// Save every record again under the id its normalized source and target hash to, for records saved before a change to
+    /// path normalization or hashing. Records that normalize to the same link are merged, keeping the most recently updated one.
+    /// The records are written to a new links folder that replaces the old one, in the current format like Compact does;
+    /// files that are not valid records are carried over for Gc. With `dry_run`, only reports what would change.
+    /// Returns how many records change id or paths, and the duplicates dropped in merging.
+    pub fn rehash(&self, dry_run: bool) -> io::Result<(usize, Vec<QuickLink>)> {
+        let mut kept: Vec<(String, QuickLink)> = Vec::new();
+        let mut merged = Vec::new();
+        for (id, mut link) in self.get_all_with_ids() {
+            link.source = normalize_path(&link.source);
+            link.target = normalize_path(&link.target);
+            match kept.iter_mut().find(|(_, other)| other.source == link.source && other.target == link.target) {
+                Some(other) if link.updated_at > other.1.updated_at => merged.push(std::mem::replace(other, (id, link)).1),
+                Some(_) => merged.push(link),
+                None => kept.push((id, link)),
+            }
+        }
+        let staging_folder = self.folder_path.join("rehash");
+        let staged = LinkStorage {
+            link_folder: staging_folder.join("links"),
+            folder_path: staging_folder.clone(),
+            compress: self.compress,
+            cache: RefCell::new(Some(HashMap::new())),
+            softlinks: RefCell::new(None),
+            index: RefCell::new(None),
+            batching: Cell::new(false),
+            index_stale: Cell::new(false),
         };
-        self.get_all().into_iter().find(|l| l.target == abs_target)
+        let records = self.records();
+        let mut moved = 0;
+        for (id, link) in &kept {
+            let (new_id, _) = staged.find_slot(&link.source, &link.target);
+            let unchanged = records.get(id).is_some_and(|stored| stored.source.as_os_str() == link.source.as_os_str() && stored.target.as_os_str() == link.target.as_os_str());
+            if new_id != *id || !unchanged {
+                debug!("Record {} moves to {}", id, new_id);
+                moved += 1;
+            }
+            if let Some(records) = staged.cache.borrow_mut().as_mut() {
+                records.insert(new_id, link.clone());
+            }
+        }
+        drop(records);
+        if dry_run || (moved == 0 && merged.is_empty()) {
+            return Ok((moved, merged));
+        }
+
+        if staging_folder.exists() {
+            std::fs::remove_dir_all(&staging_folder)?; // left by an interrupted rehash
+        }
+        std::fs::create_dir_all(&staged.link_folder)?;
+        staged.refresh();
+        staged.batch(|| kept.iter().try_for_each(|(_, link)| staged.save_quicklink(link)))??;
+        for entry in self.link_folder.read_dir()?.flatten() {
+            if read_record(&entry.path()).is_none() {
+                std::fs::rename(entry.path(), staged.link_folder.join(entry.file_name()))?;
+            }
+        }
+        let old_folder = self.folder_path.join("links.old");
+        std::fs::rename(&self.link_folder, &old_folder)?;
+        std::fs::rename(&staged.link_folder, &self.link_folder)?;
+        std::fs::remove_dir_all(&old_folder)?;
+        std::fs::remove_dir_all(&staging_folder)?;
+        self.reindex()?;
+        Ok((moved, merged))
     }
 
-    /// Get all saved QuickLinks as a Vec
-    pub fn get_all(&self) -> Vec<QuickLink> {
-        let mut links = Vec::new();
+    /// Check that records can be saved, by creating and deleting a probe file in the links folder
+    pub fn check_writable(&self) -> io::Result<()> {
+        let probe = self.link_folder.join("probe.tmp");
+        File::create(&probe)?;
+        remove_file(&probe)
+    }
+
+    /// Files in the links folder that no lookup can reach, each with the reason, sorted by path
+    pub fn orphan_records(&self) -> Vec<(PathBuf, OrphanReason)> {
+        let mut orphans = Vec::new();
         if let Ok(entries) = self.link_folder.read_dir() {
             for entry in entries.flatten() {
-                if let Ok(file) = File::open(entry.path()) {
-                    if let Ok(link) = serde_json::from_reader::<_, QuickLink>(BufReader::new(file)) {
-                        links.push(link);
-                    }
+                let path = entry.path();
+                if entry.metadata().is_ok_and(|meta| meta.len() == 0) {
+                    orphans.push((path, OrphanReason::Empty));
+                    continue;
+                }
+                let Some(link) = read_record(&path) else {
+                    orphans.push((path, OrphanReason::Invalid));
+                    continue;
+                };
+                let (id, _) = self.find_slot(&link.source, &link.target);
+                if self.record_path(&id).as_ref() != Some(&path) {
+                    orphans.push((path, OrphanReason::Unreachable));
                 }
             }
         }
-        links
+        orphans.sort_by(|a, b| a.0.cmp(&b.0));
+        orphans
     }
 
-    /// Save a QuickLink to a file named by a hash of its source and target path
-    pub fn save_quicklink(&self, link: &QuickLink) {
-        let source_str = link.source.to_string_lossy();
-        let target_str = link.target.to_string_lossy();
-        let hash = hash_source_target(&source_str, &target_str);
-        let target_file = OpenOptions::new().read(true).write(true).truncate(true).create(true)
-                        .open(self.link_folder.join(hash)).unwrap();
-        let mut target_file_writer = BufWriter::new(target_file);
-        let serialized = serde_json::to_string(link).unwrap();
-        target_file_writer.write(serialized.as_bytes()).unwrap();
+    /// Path of the record file for `hash`, whichever format it is stored in
+    fn record_path(&self, hash: &str) -> Option<PathBuf> {
+        let plain_path = self.link_folder.join(hash);
+        if plain_path.exists() {
+            return Some(plain_path);
+        }
+        let compressed_path = self.link_folder.join(format!("{}.{}", hash, COMPRESSED_EXTENSION));
+        compressed_path.exists().then_some(compressed_path)
     }
 
-    pub fn init(initial_path: &PathBuf) -> LinkStorage {
-        if !dir_contains(&initial_path, ".fslink") {
-            create_dir(initial_path.join(".fslink")).unwrap();
+    /// The (target, hash) pairs sorted by target, loading them from the index file if they have not been yet,
+    /// or rebuilding the file if it is missing. If the rebuilt index cannot be written, e.g. in a read-only database, it is only used in memory.
+    fn load_index(&self) -> Ref<'_, Vec<(String, String)>> {
+        if self.index.borrow().is_none() {
+            let loaded = File::open(self.folder_path.join("targets.idx")).ok()
+                .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok());
+            let index = match loaded {
+                Some(index) => index,
+                None => {
+                    self.refresh();
+                    let index = self.build_index();
+                    if let Err(e) = self.write_index(&index) {
+                        warn!("Cannot write the target index, using it in memory only: {}", e);
+                    }
+                    index
+                },
+            };
+            *self.index.borrow_mut() = Some(index);
+        }
+        Ref::map(self.index.borrow(), |index| index.as_ref().unwrap())
+    }
+
+    /// Apply `change` to the target index and write it if it returns true. While a batch runs, the outdated index file
+    /// is only removed, and the index is written when the batch ends.
+    fn update_index(&self, change: impl FnOnce(&mut Vec<(String, String)>) -> bool) -> io::Result<()> {
+        drop(self.load_index());
+        let mut cached = self.index.borrow_mut();
+        let index = cached.as_mut().unwrap();
+        if !change(index) {
+            return Ok(());
+        }
+        if !self.batching.get() {
+            return self.write_index(index);
         }
-        LinkStorage::new(initial_path)
+        if !self.index_stale.replace(true) && let Err(e) = remove_file(self.folder_path.join("targets.idx")) && e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+        Ok(())
+    }
 
+    /// Rebuild the sorted target index from the saved records, reading them again from the links folder
+    pub fn reindex(&self) -> io::Result<Vec<(String, String)>> {
+        self.refresh();
+        let index = self.build_index();
+        self.write_index(&index)?;
+        *self.index.borrow_mut() = Some(index.clone());
+        Ok(index)
+    }
+
+    /// The (target, hash) pairs of the cached records, sorted by target
+    fn build_index(&self) -> Vec<(String, String)> {
+        let mut index: Vec<(String, String)> = self.records().iter()
+            .map(|(id, link)| (link.target.to_string_lossy().into_owned(), id.clone()))
+            .collect();
+        index.sort();
+        index
+    }
+
+    fn write_index(&self, index: &[(String, String)]) -> io::Result<()> {
+        let index_file = File::create(self.folder_path.join("targets.idx"))?;
+        let mut writer = BufWriter::new(index_file);
+        serde_json::to_writer(&mut writer, index)?;
+        writer.flush()
     }
-}
 
-fn dir_contains(directory: &PathBuf, target_name: &str) -> bool {
-    for e in directory.read_dir().expect("Failed to read initial search directory") {
-        let entry = e.unwrap();
-        if entry.file_name() == target_name {
-            return true;
+    /// Write every saved QuickLink to `writer`, one JSON object per line
+    pub fn export_all<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for link in self.get_all() {
+            serde_json::to_writer(&mut writer, &link)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+
+    /// Save QuickLinks read from an `export_all` dump, resolving records that already exist per `strategy`.
+    /// With `dry_run`, nothing is saved. Returns the restored links and the number of skipped records.
+    pub fn import_all<R: BufRead>(&self, reader: R, strategy: RestoreStrategy, dry_run: bool) -> io::Result<(Vec<QuickLink>, usize)> {
+        let mut links = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                links.push(serde_json::from_str::<QuickLink>(&line)?);
             }
         }
-    false
+        let is_conflict = |link: &QuickLink| self.get_quicklink(&link.source, &link.target).is_some();
+        if strategy == RestoreStrategy::Fail {
+            let conflicts: Vec<String> = links.iter().filter(|l| is_conflict(l)).map(|l| l.target.to_string_lossy().into_owned()).collect();
+            if !conflicts.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("records already exist for targets: {}", conflicts.join(", "))));
+            }
+        }
+        let (mut restored, mut skipped) = (Vec::new(), 0);
+        self.batch(|| {
+            for link in links {
+                if strategy == RestoreStrategy::Skip && is_conflict(&link) {
+                    skipped += 1;
+                    continue;
+                }
+                if !dry_run {
+                    self.save_quicklink(&link)?;
+                }
+                restored.push(link);
+            }
+            Ok::<_, io::Error>(())
+        })??;
+        Ok((restored, skipped))
+    }
+
+    /// Create a database in `directory`. Fails if one already exists there, unless `force` is set,
+    /// in which case the existing database is kept and only missing folders are created.
+    pub fn create(directory: &Path, force: bool) -> Result<LinkStorage, StorageError> {
+        let folder_path = directory.join(".fslink");
+        if folder_path.is_dir() {
+            if !force {
+                return Err(StorageError::DatabaseExists(folder_path));
+            }
+        } else {
+            create_dir(&folder_path).map_err(|e| StorageError::at(&folder_path, e))?;
+            let version_path = folder_path.join("version");
+            std::fs::write(&version_path, format!("{}\n", SCHEMA_VERSION)).map_err(|e| StorageError::at(&version_path, e))?;
+        }
+        LinkStorage::open(directory)
+    }
+}
+
+/// Read a record file, decompressing it if it has the compressed extension
+fn read_record(path: &Path) -> Option<QuickLink> {
+    let reader = BufReader::new(File::open(path).ok()?);
+    if path.extension().is_some_and(|extension| extension == COMPRESSED_EXTENSION) {
+        serde_json::from_reader(GzDecoder::new(reader)).ok()
+    } else {
+        serde_json::from_reader(reader).ok()
+    }
 }
 
-/// Hash source and target path to a hex string using Blake2b
-fn hash_source_target(source: &str, target: &str) -> String {
+/// Record ids a link with `hash` may be saved under: the hash itself, then `hash-1`, `hash-2`, ... after collisions
+fn collision_ids(hash: &str) -> impl Iterator<Item = String> {
+    std::iter::once(hash.to_string()).chain((1..).map(move |n| format!("{}-{}", hash, n)))
+}
+
+/// Hash source and target path to a hex string using Blake2b.
+/// The raw bytes of the paths are hashed, so paths that are not valid UTF-8 get distinct ids too.
+fn hash_source_target(source: &Path, target: &Path) -> String {
     let mut hasher = Blake2b512::new();
-    hasher.update(source.as_bytes());
+    hasher.update(source.as_os_str().as_encoded_bytes());
     hasher.update(b"|");
-    hasher.update(target.as_bytes());
+    hasher.update(target.as_os_str().as_encoded_bytes());
     let result = hasher.finalize();
     hex::encode(&result[..16]) // Use first 16 bytes for brevity
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{scratch_dir, scratch_storage};
+
+    /// Two links, and the id the first one hashes to
+    fn colliding_links(dir: &Path) -> (QuickLink, QuickLink, String) {
+        let first = QuickLink { source: dir.join("a"), target: dir.join("first"), ..Default::default() };
+        let second = QuickLink { source: dir.join("b"), target: dir.join("second"), ..Default::default() };
+        let hash = hash_source_target(&first.source, &first.target);
+        (first, second, hash)
+    }
+
+    #[test]
+    fn colliding_record_is_saved_under_next_id() {
+        let dir = scratch_dir("collision-save");
+        let db = scratch_storage(&dir);
+        let (first, second, hash) = colliding_links(&dir);
+        let collided = format!("{}-1", hash);
+        // Another record already holding the hash pushes the link to the next collision id
+        db.save_at(hash.clone(), &second).unwrap();
+        db.save_quicklink(&first).unwrap();
+        assert_eq!(db.record_id(&first), collided);
+        assert_eq!(db.get_quicklink(&first.source, &first.target).map(|link| link.target), Some(first.target.clone()));
+        assert_eq!(db.get_by_id(&hash).map(|link| link.target), Some(second.target.clone()));
+        db.remove_quicklink(&first).unwrap();
+        db.refresh();
+        assert_eq!(db.get_by_id(&hash).map(|link| link.target), Some(second.target.clone()));
+        assert!(db.get_by_id(&collided).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removal_moves_colliding_record_into_gap() {
+        let dir = scratch_dir("collision-remove");
+        let db = scratch_storage(&dir);
+        let (first, second, hash) = colliding_links(&dir);
+        let collided = format!("{}-1", hash);
+        db.save_quicklink(&first).unwrap();
+        db.save_at(collided.clone(), &second).unwrap();
+        db.remove_quicklink(&first).unwrap();
+        db.refresh();
+        assert_eq!(db.get_by_id(&hash).map(|link| link.target), Some(second.target.clone()));
+        assert!(db.get_by_id(&collided).is_none());
+        assert_eq!(db.find_by_target_prefix(&second.target.to_string_lossy()).len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn target_is_found_by_relative_and_absolute_paths() {
+        let dir = scratch_dir("find-target");
+        let db = scratch_storage(&dir);
+        let link = QuickLink { source: dir.join("source"), target: dir.join("sub").join("target"), ..Default::default() };
+        db.save_quicklink(&link).unwrap();
+        let found = |target: &Path| db.find_by_target(target).map(|link| link.target);
+        // Tests share the current directory, so the relative paths lead from it rather than changing it
+        let relative = crate::relative_link_path(&link.target, &std::env::current_dir().unwrap());
+        assert!(relative.is_relative());
+        assert_eq!(found(&link.target), Some(link.target.clone()));
+        assert_eq!(found(&relative), Some(link.target.clone()));
+        assert_eq!(found(&relative.parent().unwrap().join("..").join("sub").join(".").join("target")), Some(link.target.clone()));
+        assert_eq!(found(&dir.join("other").join("..").join("sub").join("target")), Some(link.target.clone()));
+        assert_eq!(found(&dir.join("sub").join("..").join("target")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = scratch_dir("non-utf8");
+        let db = scratch_storage(&dir);
+        let target = dir.join(std::ffi::OsStr::from_bytes(b"target-\xff"));
+        let link = QuickLink { source: dir.join("source"), target: target.clone(), ..Default::default() };
+
+        let json = serde_json::to_string(&link).unwrap();
+        let read: QuickLink = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.target, target);
+
+        let lossy = QuickLink { target: PathBuf::from(target.to_string_lossy().into_owned()), ..link.clone() };
+        assert_ne!(db.record_id(&link), db.record_id(&lossy));
+        db.save_quicklink(&link).unwrap();
+        db.refresh();
+        assert_eq!(db.get_quicklink(&link.source, &target).map(|link| link.target), Some(target.clone()));
+        assert_eq!(db.record_id(&link), hash_source_target(&link.source, &target));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
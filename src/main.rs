@@ -1,85 +1,1734 @@
-// Helper to get absolute path even if file doesn't exist
-fn absolute_path(path: &Path) -> PathBuf {
-    if path.is_absolute() {
+// Helper to get absolute path even if file doesn't exist, resolving relative paths against `base`
+fn absolute_path(path: &Path, base: &Path) -> PathBuf {
+    let joined = if path.is_absolute() {
         path.to_path_buf()
     } else {
-        env::current_dir().unwrap().join(path)
+        base.join(path)
+    };
+    normalize_path(&joined)
+}
+/// Resolve `.` and `..` components lexically, without touching the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                },
+                Some(Component::RootDir) => {}, // `..` of the root is the root itself
+                _ => normalized.push(component), // A relative path stepping further out keeps its `..`
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+/// Path of the link for `source` given as `target`: inside `target`, named like the source, if it is an existing
+/// directory (not a symlink to one) or ends with a slash, as with `ln`. Otherwise `target` itself
+fn link_path_in(source: &Path, target: &Path, base: &Path) -> PathBuf {
+    let abs_target = absolute_path(target, base);
+    let names_directory = target.as_os_str().as_encoded_bytes().last().is_some_and(|byte| std::path::is_separator(*byte as char)) || abs_target.symlink_metadata().is_ok_and(|meta| meta.is_dir());
+    match source.file_name() {
+        Some(name) if names_directory => abs_target.join(name),
+        _ => abs_target,
+    }
+}
+/// Path leading from the directory `from` to `path`, both absolute, stepping out with `..` as needed
+fn relative_link_path(path: &Path, from: &Path) -> PathBuf {
+    let common = path.components().zip(from.components()).take_while(|(a, b)| a == b).count();
+    let mut relative: PathBuf = from.components().skip(common).map(|_| Component::ParentDir).collect();
+    relative.extend(path.components().skip(common));
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+/// What a symlink at `target` has to contain to point to `source`
+fn symlink_destination(source: &Path, target: &Path, relative: bool) -> PathBuf {
+    match target.parent() {
+        Some(directory) if relative => relative_link_path(source, directory),
+        _ => source.to_path_buf(),
+    }
+}
+/// `path` relative to `base` if it lies inside it, otherwise unchanged
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    match path.strip_prefix(base) {
+        Ok(relative) if relative.as_os_str().is_empty() => PathBuf::from("."),
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path.to_path_buf(),
+    }
+}
+mod database;
+mod cli;
+#[cfg(unix)]
+mod serve;
+mod template;
+mod platform;
+mod watch;
+
+use std::{env, error::Error, fmt::{self, Debug, Display, Formatter}, io::{self, IsTerminal, Write}, path::{Component, Path, PathBuf}};
+use clap::ValueEnum;
+use std::fs::{read_link, File};
+use std::io::{BufRead, BufReader, BufWriter};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{path_bytes, JournalEntry, LinkStorage, OrphanReason, StorageError};
+use crate::cli::{Cli, Commands, OutputFormat};
+use crate::template::Template;
+
+fn main() -> std::io::Result<()> {
+    let cli = Cli::parse_args();
+    env_logger::Builder::new().filter_level(cli.log_level()).parse_default_env().init();
+    let current_dir = env::current_dir().unwrap();
+    let database = cli.database.as_deref().map(|dir| absolute_path(dir, &current_dir));
+    if let Commands::Completions { shell } = cli.command {
+        cli::print_completions(shell);
+        return Ok(());
+    }
+    if let Commands::Init { dir, force } = &cli.command {
+        let dir = match (dir, &database) {
+            (Some(dir), _) => absolute_path(dir, &current_dir),
+            (None, Some(database)) => {
+                // A central database directory may not exist yet
+                if let Err(e) = std::fs::create_dir_all(database) {
+                    eprintln!("Cannot create {}: {}", database.display(), e);
+                    Failure::Error.exit();
+                }
+                database.clone()
+            },
+            (None, None) => current_dir.clone(),
+        };
+        match LinkStorage::create(&dir, *force) {
+            Ok(_) => println!("Initialized database in {}", dir.join(".fslink").display()),
+            Err(e) => {
+                eprintln!("{}", e);
+                Failure::of_storage(&e).exit();
+            }
+        }
+        return Ok(());
+    }
+    let dry_run = cli.dry_run;
+    let yes = cli.yes;
+    let opened = match &database {
+        Some(database) => LinkStorage::open(database),
+        None if cli.no_parent => LinkStorage::open(&current_dir),
+        None => LinkStorage::new(&current_dir),
+    };
+    let db = match opened {
+        Ok(db) => db.compressed(cli.compress),
+        Err(e) => {
+            eprintln!("{}", e);
+            Failure::of_storage(&e).exit();
+        }
+    };
+    let mut output = Output { json: cli.json, failed: None };
+    let base = cli.resolve_relative_to.map_or_else(|| current_dir.clone(), |dir| absolute_path(&dir, &current_dir));
+
+    match cli.command {
+        Commands::Create { source, target, link_type, target_dir, more_sources, max_records, type_flag, record_invocation, warn_shadow, strict, replace_if_different, allow_missing_source, only_on, relative, recursive, tag, backup, force, no_target_directory, name, preserve, keep_partial, note, fallback_symlink } => {
+            let link_type = link_type.or(type_flag).unwrap_or_default();
+            let pattern = source_glob(&source, &base).filter(|_| more_sources.is_empty());
+            let source = absolute_path(&source, &base);
+            let sources: Vec<PathBuf> = std::iter::once(source.clone()).chain(more_sources.iter().map(|source| absolute_path(source, &base))).collect();
+            let options = CreateOptions {
+                origin_args: record_invocation.then(|| env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect()),
+                warn_shadow,
+                strict,
+                replace_if_different,
+                allow_missing_source,
+                only_on,
+                relative,
+                dry_run,
+                tags: tag,
+                backup,
+                force,
+                yes,
+                name,
+                preserve,
+                note,
+                fallback_symlink,
+            };
+            // Source and target roots of a --recursive --preserve mirror, to copy directory modes between
+            let mirror = target.as_ref().filter(|_| recursive && preserve).map(|target| (source.clone(), absolute_path(target, &base)));
+            let planned: Vec<(PathBuf, PathBuf)> = match (target, pattern) {
+                (target, Some(pattern)) => {
+                    let matches = match glob::glob(&pattern) {
+                        Ok(paths) => paths.filter_map(Result::ok).collect::<Vec<PathBuf>>(),
+                        Err(e) => {
+                            eprintln!("Invalid source pattern '{}': {}", pattern, e);
+                            Failure::Error.exit();
+                        }
+                    };
+                    if matches.is_empty() {
+                        eprintln!("No files match '{}'", pattern);
+                        Failure::NotFound.exit();
+                    }
+                    let directories: Vec<PathBuf> = match target.map(|target| absolute_path(&target, &base)) {
+                        Some(target) if matches.len() == 1 && !target.is_dir() => vec![target],
+                        Some(target) if !target.is_dir() => {
+                            eprintln!("Target '{}' must be a directory to link the {} files matching '{}' into", target.display(), matches.len(), pattern);
+                            Failure::Error.exit();
+                        },
+                        Some(target) => vec![target],
+                        None => target_dir.iter().map(|dir| absolute_path(dir, &base)).collect(),
+                    };
+                    directories.iter().flat_map(|dir| matches.iter().map(move |file| match file.file_name() {
+                        Some(name) if dir.is_dir() => (file.clone(), dir.join(name)),
+                        _ => (file.clone(), dir.clone()),
+                    })).collect()
+                },
+                (Some(target), None) if recursive => {
+                    if !source.is_dir() {
+                        eprintln!("Source '{}' is not a directory, which --recursive needs", source.display());
+                        Failure::Error.exit();
+                    }
+                    let target = absolute_path(&target, &base);
+                    source_tree_files(&source)?.into_iter()
+                        .map(|file| {
+                            let mirrored = target.join(file.strip_prefix(&source).unwrap());
+                            (file, mirrored)
+                        })
+                        .collect()
+                },
+                (Some(target), None) => {
+                    let target = if no_target_directory { absolute_path(&target, &base) } else { link_path_in(&source, &target, &base) };
+                    match create_link(&db, &source, &target, link_type, &options, &[]) {
+                        Ok(CreateOutcome::Created(link)) => output.emit(CommandResult::Done { operation: "create", dry_run, link: link_json(&db, &link) },
+                            format!("{}Link created: {}", dry_run_prefix(dry_run), link)),
+                        Ok(CreateOutcome::Unchanged(link)) => output.emit(CommandResult::Unchanged { operation: "create", link: link_json(&db, &link) },
+                            format!("Link already in place: {}", link)),
+                        Ok(CreateOutcome::Skipped(link)) => output.emit(CommandResult::Done { operation: "create", dry_run, link: link_json(&db, &link) },
+                            format!("{}Link recorded but not linked, its conditions do not match this machine: {}", dry_run_prefix(dry_run), link)),
+                        Err(e) => output.emit(CommandResult::Error { operation: "create", error: e.to_string(), failure: e.failure() },
+                            format!("Error creating link at {}: {}", target.display(), e)),
+                    }
+                    output.finish();
+                    return Ok(());
+                },
+                (None, None) => {
+                    let mut planned = Vec::new();
+                    for source in &sources {
+                        let Some(file_name) = source.file_name() else {
+                            eprintln!("Source '{}' has no file name to name the links by", source.display());
+                            Failure::Error.exit();
+                        };
+                        planned.extend(target_dir.iter().map(|dir| (source.clone(), absolute_path(dir, &base).join(file_name))));
+                    }
+                    planned
+                },
+            };
+            if planned.len() > max_records {
+                eprintln!("Refusing to create {} links, more than the limit of {} (raise it with --max-records)", planned.len(), max_records);
+                Failure::Error.exit();
+            }
+            if !check_free_inodes(&planned, strict) {
+                Failure::Error.exit();
+            }
+            // Directories created so far for a recursive Create, recorded on the links inside them
+            let mut made_dirs: Vec<PathBuf> = Vec::new();
+            let create = |source: &Path, target: &Path| {
+                if recursive && !dry_run && let Some(parent) = target.parent() {
+                    made_dirs.extend(create_mirrored_dirs(parent, mirror.as_ref())?);
+                }
+                let mut created_dirs: Vec<PathBuf> = made_dirs.iter().filter(|dir| target.starts_with(dir)).cloned().collect();
+                created_dirs.sort_by_key(|dir| Reverse(dir.components().count()));
+                let created = create_link(&db, source, target, link_type, &options, &created_dirs);
+                if created.is_err() {
+                    remove_empty_dirs(&created_dirs);
+                    made_dirs.retain(|dir| dir.exists());
+                }
+                created
+            };
+            match db.batch(|| apply_all(&db, &planned, keep_partial, dry_run, &mut output, create))? {
+                Ok(linked) => output.note(format!("{}{} of {} targets linked", dry_run_prefix(dry_run), linked, planned.len())),
+                Err(BatchFailure { target, error, rolled_back }) => output.emit(
+                    CommandResult::Error { operation: "create", error: format!("{}: {}, rolled back {} created links", target.display(), error, rolled_back), failure: error.failure() },
+                    format!("{}Error creating link at {}: {}; rolled back the {} links created before it", dry_run_prefix(dry_run), target.display(), error, rolled_back)),
+            }
+        }
+        Commands::Link { source, target, link_type } => {
+            let source = absolute_path(&source, &base);
+            let target = link_path_in(&source, &target, &base);
+            let already_present = target.symlink_metadata().is_ok();
+            let created = match (db.get_quicklink(&source, &target), find_link_cycle(&db, &source, &target)) {
+                (Some(link), _) if link.linktype != link_type => Err(QuickLinkCreationError::TrackedAsOtherType(source.to_string_lossy().into_owned(), target.to_string_lossy().into_owned(), link.linktype)),
+                (Some(link), _) if link.is_in_place() => {
+                    println!("Link already tracked: {}", link);
+                    return Ok(());
+                },
+                // Tracked, but toggled off or missing on disk
+                (Some(mut link), _) => match QuickLink::check_source(&link.source, link.linktype) {
+                    Err(e) => Err(e),
+                    Ok(()) if already_present => Err(QuickLinkCreationError::TargetExists(source.to_string_lossy().into_owned(), target.to_string_lossy().into_owned())),
+                    Ok(()) if dry_run => Ok(link),
+                    Ok(()) => link.link().map(|()| link).map_err(QuickLinkCreationError::from),
+                },
+                (None, chain) => match chain {
+                    Some(chain) if link_type == LinkType::Softlink => Err(QuickLinkCreationError::WouldCreateCycle(target.to_string_lossy().into_owned(), chain.iter().map(|path| path.display().to_string()).collect())),
+                    _ if dry_run => QuickLink::new(&source, &target, link_type),
+                    _ => QuickLink::new_autolink(&source, &target, link_type),
+                },
+            };
+            match created {
+                Ok(mut link) => {
+                    if !dry_run {
+                        link.session_id = Some(session_id().to_string());
+                        db.save_quicklink(&link)?;
+                    }
+                    if already_present {
+                        println!("{}Link already present, now tracked: {}", dry_run_prefix(dry_run), link);
+                    } else {
+                        println!("{}Link created: {}", dry_run_prefix(dry_run), link);
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Error creating link: {}", e);
+                    e.failure().exit();
+                }
+            }
+        }
+        Commands::Init { .. } | Commands::Completions { .. } => unreachable!("Init and Completions are handled before opening the database"),
+        Commands::Remove { source, tag, restore_backup, force, .. } if source.is_some() || tag.is_some() => {
+            let (links, selector) = match (source, tag) {
+                (Some(source), _) => (db.find_all_by_source(&absolute_path(&source, &base)), format!("source: {}", source.display())),
+                (None, Some(tag)) => (db.find_by_tag(&tag), format!("tag: {}", tag)),
+                (None, None) => unreachable!("guarded by the match arm"),
+            };
+            if links.is_empty() {
+                let message = format!("No tracked links found for {}", selector);
+                output.emit(CommandResult::Error { operation: "remove", error: message.clone(), failure: Failure::NotFound }, message);
+                output.finish();
+                return Ok(());
+            }
+            let mut removed = 0;
+            for mut link in links {
+                if !link.exists {
+                    if !dry_run {
+                        db.remove_quicklink(&link)?;
+                    }
+                    output.emit(CommandResult::Unchanged { operation: "remove", link: link_json(&db, &link) },
+                        format!("{}Link not present in filesystem, forgetting it: {}", dry_run_prefix(dry_run), link));
+                    continue;
+                }
+                if !force && let Err(e) = link.ensure_tracked_link() {
+                    let message = format!("Not removing {}: {} (pass --force to remove it anyway)", link.target.display(), e);
+                    output.emit(CommandResult::Error { operation: "remove", error: message.clone(), failure: Failure::Conflict }, message);
+                    continue;
+                }
+                if !dry_run && !yes && !confirm(&format!("Remove link target {}?", link.target.display())) {
+                    let message = format!("Not removing {}: not confirmed", link.target.display());
+                    output.emit(CommandResult::Error { operation: "remove", error: message.clone(), failure: Failure::Error }, message);
+                    continue;
+                }
+                if !dry_run {
+                    link.unlink_forced()?;
+                }
+                removed += 1;
+                output.emit(CommandResult::Done { operation: "remove", dry_run, link: link_json(&db, &link) },
+                    format!("{}Link removed: {}", dry_run_prefix(dry_run), link));
+                if restore_backup {
+                    restore_link_backup(&db, &mut link, dry_run, &output)?;
+                }
+                if !dry_run {
+                    remove_empty_dirs(&link.created_dirs);
+                    db.remove_quicklink(&link)?;
+                    db.journal().push(&JournalEntry::Relink { source: link.source.clone(), target: link.target.clone(), record: Some(Box::new(link)) })?;
+                }
+            }
+            output.note(format!("{}{} links removed", dry_run_prefix(dry_run), removed));
+        }
+        Commands::Remove { target, by_id, name, restore_backup, force, .. } => {
+            match find_link(&db, target.as_deref(), by_id.as_deref(), name.as_deref(), &base) {
+                Ok(mut link) => {
+                    if !link.exists {
+                        output.emit(CommandResult::Unchanged { operation: "remove", link: link_json(&db, &link) }, format!("Link not present in filesystem: {}", link));
+                    } else if !force && let Err(e) = link.ensure_tracked_link() {
+                        let message = format!("Not removing {}: {} (pass --force to remove it anyway)", link.target.display(), e);
+                        output.emit(CommandResult::Error { operation: "remove", error: message.clone(), failure: Failure::Conflict }, message);
+                    } else if !dry_run && !yes && !confirm(&format!("Remove link target {}?", link.target.display())) {
+                        let message = format!("Not removing {}: not confirmed", link.target.display());
+                        output.emit(CommandResult::Error { operation: "remove", error: message.clone(), failure: Failure::Error }, message);
+                    } else {
+                        if !dry_run {
+                            link.unlink_forced()?;
+                            db.save_quicklink(&link)?;
+                            db.journal().push(&JournalEntry::Relink { source: link.source.clone(), target: link.target.clone(), record: None })?;
+                        }
+                        output.emit(CommandResult::Done { operation: "remove", dry_run, link: link_json(&db, &link) },
+                            format!("{}Link removed: {}", dry_run_prefix(dry_run), link));
+                        if restore_backup {
+                            restore_link_backup(&db, &mut link, dry_run, &output)?;
+                        }
+                        if !dry_run {
+                            remove_empty_dirs(&link.created_dirs);
+                        }
+                    }
+                },
+                Err(message) => {
+                    output.emit(CommandResult::Error { operation: "remove", error: message.clone(), failure: Failure::NotFound }, message);
+                }
+            }
+        }
+        Commands::List { print0, no_footer, relative_to, session, tag, link_type, existing, missing, under, template, format } => {
+            let relative_to = relative_to.map(|dir| dir.map_or_else(|| db.root().to_path_buf(), |dir| absolute_path(&dir, &current_dir)));
+            let under = under.map(|dir| absolute_path(&dir, &base));
+            let links: Vec<QuickLink> = db.get_all().into_iter()
+                .filter(|link| session.is_none() || link.session_id == session)
+                .filter(|link| tag.as_ref().is_none_or(|tag| link.tags.contains(tag)))
+                .filter(|link| link_type.is_none_or(|link_type| link.linktype == link_type))
+                .filter(|link| (!existing || link.exists) && (!missing || !link.exists))
+                .filter(|link| under.as_ref().is_none_or(|dir| link.source.starts_with(dir) || link.target.starts_with(dir)))
+                .collect();
+            if print0 {
+                let mut out = io::stdout().lock();
+                for link in links {
+                    out.write_all(link.target.as_os_str().as_encoded_bytes())?;
+                    out.write_all(b"\0")?;
+                }
+                out.flush()?;
+                return Ok(());
+            }
+            if let Some(template) = template {
+                match Template::parse(&template) {
+                    Ok(template) => links.iter().for_each(|link| println!("{}", template.render(link, &db))),
+                    Err(e) => {
+                        eprintln!("Invalid template: {}", e);
+                        Failure::Error.exit();
+                    }
+                }
+                return Ok(());
+            }
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::to_string(&links.iter().map(|link| link_json(&db, link)).collect::<Vec<_>>())?);
+                return Ok(());
+            }
+            println!("Tracked links:");
+            for link in &links {
+                match &relative_to {
+                    Some(dir) => println!("{}", link.display_relative(dir)),
+                    None => println!("{}", link),
+                }
+            }
+            let broken = links.iter().filter(|link| link.applies_here() && link.state() != LinkState::Ok).count();
+            if !no_footer && broken > 0 {
+                println!("{} of {} links are broken (run `check` for details)", broken, links.len());
+            }
+        }
+        Commands::Complete { prefix } => {
+            for link in db.find_by_target_prefix(&absolute_path(&prefix.unwrap_or_default(), &base).to_string_lossy()) {
+                println!("{}", link.target.display());
+            }
+        }
+        Commands::Dependents { source, format } => {
+            let dependents = db.find_by_source_prefix(&absolute_path(&source, &base));
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(&dependents.iter().map(|link| link_json(&db, link)).collect::<Vec<_>>())?),
+                OutputFormat::Text => {
+                    for link in &dependents {
+                        println!("{}", link);
+                    }
+                },
+            }
+            if dependents.is_empty() {
+                Failure::NotFound.exit();
+            }
+        }
+        Commands::Check { check_loops } => {
+            let mut discrepancies = 0;
+            for link in db.get_all().into_iter().filter(QuickLink::applies_here) {
+                let state = link.state();
+                let broken_chain = (check_loops && link.linktype == LinkType::Softlink && link.target.is_symlink())
+                    .then(|| follow_symlink_chain(&link.target))
+                    .filter(|(_, end)| *end != ChainEnd::Resolved)
+                    .map(|(chain, end)| (end, chain.iter().map(|hop| hop.display().to_string()).collect::<Vec<_>>().join(" -> ")));
+                // A link is reported once, with its broken chain added to its state if both are off
+                match (state, broken_chain) {
+                    (LinkState::Ok, None) => continue,
+                    (LinkState::Ok, Some((end, hops))) => println!("{}: {} ({})", end, link, hops),
+                    (state, None) => println!("{}: {} ({})", state, link, state.describe()),
+                    (state, Some((end, hops))) => println!("{}: {} ({}; {}: {})", state, link, state.describe(), end, hops),
+                }
+                discrepancies += 1;
+            }
+            if discrepancies > 0 {
+                eprintln!("{} links do not match the database", discrepancies);
+                Failure::Error.exit();
+            }
+            println!("All tracked links match the database");
+        }
+        Commands::Status => {
+            let mut not_ok = 0;
+            for link in db.get_all().into_iter().filter(QuickLink::applies_here) {
+                let state = link.state();
+                if state != LinkState::Ok {
+                    not_ok += 1;
+                }
+                output.emit(CommandResult::State { state: state.to_string(), link: link_json(&db, &link) }, format!("{:<10} {}", state.to_string(), link));
+            }
+            if not_ok > 0 {
+                Failure::Error.exit();
+            }
+        }
+        Commands::Count => {
+            let links = db.get_all();
+            let softlinks = links.iter().filter(|link| link.linktype == LinkType::Softlink).count();
+            let present = links.iter().filter(|link| link.is_in_place()).count();
+            let dangling = links.iter().filter(|link| !link.deferred && link.source.symlink_metadata().is_err()).count();
+            if output.json {
+                println!("{}", serde_json::json!({
+                    "total": links.len(), "softlinks": softlinks, "hardlinks": links.len() - softlinks,
+                    "present": present, "missing": links.len() - present, "dangling": dangling,
+                }));
+            } else {
+                println!("Links: {} ({} softlinks, {} hardlinks)", links.len(), softlinks, links.len() - softlinks);
+                println!("Present: {}, missing: {}", present, links.len() - present);
+                println!("Dangling: {}", dangling);
+            }
+        }
+        Commands::Info { target, by_id, relative_to } => {
+            let relative_to = relative_to.map(|dir| dir.map_or_else(|| db.root().to_path_buf(), |dir| absolute_path(&dir, &current_dir)));
+            match find_link(&db, target.as_deref(), by_id.as_deref(), None, &base) {
+                Ok(link) => {
+                    let shown = |path: &Path| relative_to.as_deref().map_or_else(|| path.to_path_buf(), |dir| relative_path(path, dir));
+                    println!("Id: {}", db.record_id(&link));
+                    println!("Source: {}", shown(&link.source).display());
+                    println!("Target: {}", shown(&link.target).display());
+                    println!("Type: {}", link.linktype);
+                    println!("Exists: {}", link.exists);
+                    if let Some(session) = &link.session_id {
+                        println!("Session: {}", session);
+                    }
+                    if let Some(args) = &link.origin_args {
+                        println!("Invocation: {}", args.join(" "));
+                    }
+                    if let Some(note) = &link.note {
+                        println!("Note: {}", note);
+                    }
+                },
+                Err(message) => {
+                    eprintln!("{}", message);
+                    Failure::NotFound.exit();
+                }
+            }
+        }
+        Commands::Toggle { all, tag, on, off, .. } if all || tag.is_some() => {
+            let forced = on.then_some(true).or(off.then_some(false));
+            let (mut enabled, mut disabled, mut errors) = (0, 0, Vec::new());
+            let links = match tag {
+                Some(tag) => db.find_by_tag(&tag),
+                None => db.get_all(),
+            };
+            // Links restricted to other machines with --only-on are left as they are
+            for mut link in links.into_iter().filter(QuickLink::applies_here) {
+                let linked = forced.unwrap_or(!link.exists);
+                if linked == link.exists {
+                    continue;
+                }
+                if dry_run {
+                    link.exists = linked;
+                } else if let Err(e) = link.toggle_link() {
+                    errors.push(format!("{}: {}", link.target.display(), e));
+                    continue;
+                } else {
+                    db.save_quicklink(&link)?;
+                    db.journal().push(&JournalEntry::Toggle { source: link.source.clone(), target: link.target.clone() })?;
+                }
+                if linked { enabled += 1 } else { disabled += 1 }
+                output.emit(CommandResult::Done { operation: "toggle", dry_run, link: link_json(&db, &link) },
+                    format!("{}Toggled link: {}", dry_run_prefix(dry_run), link));
+            }
+            output.note(format!("{}{} links enabled, {} disabled", dry_run_prefix(dry_run), enabled, disabled));
+            if !errors.is_empty() {
+                if !output.json {
+                    eprintln!("{} links could not be toggled:", errors.len());
+                }
+                for error in errors {
+                    output.emit(CommandResult::Error { operation: "toggle", error: error.clone(), failure: Failure::Error }, format!("  {}", error));
+                }
+            }
+        }
+        Commands::Toggle { target, by_id, name, on, off, .. } => {
+            let forced = on.then_some(true).or(off.then_some(false));
+            match find_link(&db, target.as_deref(), by_id.as_deref(), name.as_deref(), &base) {
+                Ok(link) if forced == Some(link.exists) => {
+                    output.emit(CommandResult::Unchanged { operation: "toggle", link: link_json(&db, &link) },
+                        format!("Link already {}: {}", if link.exists { "enabled" } else { "disabled" }, link));
+                },
+                Ok(mut link) if dry_run => {
+                    link.exists = !link.exists;
+                    output.emit(CommandResult::Done { operation: "toggle", dry_run, link: link_json(&db, &link) },
+                        format!("{}Toggled link: {}", dry_run_prefix(dry_run), link));
+                },
+                Ok(link) if let Some(e) = link.ensure_tracked_link().err().filter(|_| link.exists) => {
+                    let message = format!("Not toggling {}: {}", link.target.display(), e);
+                    output.emit(CommandResult::Error { operation: "toggle", error: message.clone(), failure: Failure::Conflict }, message);
+                },
+                Ok(mut link) => match link.toggle_link() {
+                    Ok(()) => {
+                        db.save_quicklink(&link)?;
+                        db.journal().push(&JournalEntry::Toggle { source: link.source.clone(), target: link.target.clone() })?;
+                        output.emit(CommandResult::Done { operation: "toggle", dry_run, link: link_json(&db, &link) }, format!("Toggled link: {}", link));
+                    },
+                    Err(e) => {
+                        let message = format!("Error toggling {}: {}", link.target.display(), e);
+                        output.emit(CommandResult::Error { operation: "toggle", error: message.clone(), failure: Failure::Error }, message);
+                    },
+                },
+                Err(message) => {
+                    output.emit(CommandResult::Error { operation: "toggle", error: message.clone(), failure: Failure::NotFound }, message);
+                }
+            }
+        }
+        Commands::Import { target } => {
+            match QuickLink::from_symlink(&absolute_path(&target, &base)) {
+                Ok(link) if db.get_quicklink(&link.source, &link.target).is_some() => {
+                    println!("Link already tracked: {}", link);
+                },
+                Ok(mut link) => {
+                    if !dry_run {
+                        link.session_id = Some(session_id().to_string());
+                        db.save_quicklink(&link)?;
+                    }
+                    println!("{}Link imported: {}", dry_run_prefix(dry_run), link);
+                },
+                Err(e) => {
+                    eprintln!("Error importing link: {}", e);
+                    e.failure().exit();
+                }
+            }
+        }
+        Commands::Scan { dir, dangling } => {
+            let dir = absolute_path(&dir, &base);
+            let files = match source_tree_files(&dir) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("Error scanning {}: {}", dir.display(), e);
+                    Failure::Error.exit();
+                }
+            };
+            let (mut added, mut skipped, mut failed) = (0, 0, 0);
+            for path in files.iter().filter(|path| path.is_symlink()) {
+                if db.find_by_target(path).is_some() {
+                    skipped += 1;
+                    continue;
+                }
+                match QuickLink::from_symlink(path) {
+                    Ok(mut link) => {
+                        if dangling && !link.source.exists() {
+                            println!("Dangling symlink: {}", link);
+                        }
+                        if !dry_run {
+                            link.session_id = Some(session_id().to_string());
+                            db.save_quicklink(&link)?;
+                        }
+                        println!("{}Link imported: {}", dry_run_prefix(dry_run), link);
+                        added += 1;
+                    },
+                    Err(e) => {
+                        failed += 1;
+                        eprintln!("Error importing {}: {}", path.display(), e);
+                    }
+                }
+            }
+            println!("{}Imported {} links, skipped {} already tracked", dry_run_prefix(dry_run), added, skipped);
+            if failed > 0 {
+                Failure::Error.exit();
+            }
+        }
+        Commands::Repair => {
+            if !repair_links(&db, db.get_all(), dry_run)? {
+                Failure::Error.exit();
+            }
+        }
+        Commands::Verify { fix } => {
+            let mut mismatches = 0;
+            for mut link in db.get_all().into_iter().filter(|link| link.exists && link.applies_here()) {
+                if link.is_in_place() {
+                    continue;
+                }
+                let replaceable = link.target.symlink_metadata().map_or(true, |meta| meta.is_symlink());
+                if !fix || !replaceable {
+                    mismatches += 1;
+                    println!("MISMATCH: {}", link);
+                    continue;
+                }
+                if dry_run {
+                    println!("{}Link fixed: {}", dry_run_prefix(dry_run), link);
+                    continue;
+                }
+                if link.target.symlink_metadata().is_ok() {
+                    std::fs::remove_file(&link.target)?;
+                }
+                match link.link() {
+                    Ok(()) => {
+                        db.save_quicklink(&link)?;
+                        println!("Link fixed: {}", link);
+                    },
+                    Err(e) => {
+                        mismatches += 1;
+                        eprintln!("Error fixing {}: {}", link.target.display(), e);
+                    }
+                }
+            }
+            if mismatches > 0 {
+                eprintln!("{} links do not match their source", mismatches);
+                Failure::Error.exit();
+            }
+            println!("All present links match their source");
+        }
+        Commands::Swap { target } => {
+            match db.find_by_target(&absolute_path(&target, &base)) {
+                Some(mut link) if dry_run => {
+                    std::mem::swap(&mut link.source, &mut link.target);
+                    println!("{}Swapped link: {}", dry_run_prefix(dry_run), link);
+                },
+                Some(mut link) => {
+                    db.remove_quicklink(&link)?;
+                    let swapped = link.swap();
+                    db.save_quicklink(&link)?;
+                    match swapped {
+                        Ok(()) => println!("Swapped link: {}", link),
+                        Err(e) => {
+                            eprintln!("Error swapping link, nothing was changed: {}", e);
+                            Failure::Error.exit();
+                        }
+                    }
+                },
+                None => {
+                    eprintln!("No tracked link found for target: {}", target.display());
+                    Failure::NotFound.exit();
+                }
+            }
+        }
+        Commands::Move { target, new_target, name } => {
+            // With --name, the only positional argument is the new target
+            let (found, new_target) = match new_target {
+                Some(new_target) => (find_link(&db, Some(&target), None, None, &base), new_target),
+                None => (find_link(&db, None, None, name.as_deref(), &base), target),
+            };
+            match found {
+                Ok(mut link) if dry_run => {
+                    link.target = absolute_path(&new_target, &base);
+                    println!("{}Moved link: {}", dry_run_prefix(dry_run), link);
+                },
+                Ok(mut link) => {
+                    db.remove_quicklink(&link)?;
+                    let old_target = link.target.clone();
+                    let moved = link.relocate(&absolute_path(&new_target, &base));
+                    db.save_quicklink(&link)?;
+                    match moved {
+                        Ok(()) => {
+                            db.journal().push(&JournalEntry::Move { source: link.source.clone(), target: link.target.clone(), to: old_target })?;
+                            println!("Moved link: {}", link);
+                        },
+                        Err(e) => {
+                            eprintln!("Error moving link, nothing was changed: {}", e);
+                            Failure::Error.exit();
+                        }
+                    }
+                },
+                Err(message) => {
+                    eprintln!("{}", message);
+                    Failure::NotFound.exit();
+                }
+            }
+        }
+        Commands::Retarget { target, new_source } => {
+            let new_source = absolute_path(&new_source, &base);
+            if !new_source.exists() {
+                eprintln!("New source does not exist: {}", new_source.display());
+                Failure::NotFound.exit();
+            }
+            match db.find_by_target(&absolute_path(&target, &base)) {
+                Some(mut link) if dry_run => {
+                    link.source = new_source;
+                    println!("{}Retargeted link: {}", dry_run_prefix(dry_run), link);
+                },
+                Some(mut link) => {
+                    db.remove_quicklink(&link)?;
+                    let retargeted = link.retarget(&new_source);
+                    db.save_quicklink(&link)?;
+                    match retargeted {
+                        Ok(()) => println!("Retargeted link: {}", link),
+                        Err(e) => {
+                            eprintln!("Error retargeting link, nothing was changed: {}", e);
+                            Failure::Error.exit();
+                        }
+                    }
+                },
+                None => {
+                    eprintln!("No tracked link found for target: {}", target.display());
+                    Failure::NotFound.exit();
+                }
+            }
+        }
+        Commands::Resolve { target } => {
+            let Some(mut link) = db.find_by_target(&absolute_path(&target, &base)) else {
+                eprintln!("No tracked link found for target: {}", target.display());
+                Failure::NotFound.exit();
+            };
+            let mut visited = vec![link.target.clone()];
+            let end = loop {
+                let state = link.state();
+                println!("{} -> {} ({}, {})", link.target.display(), link.source.display(), link.linktype, state);
+                if visited.contains(&link.source) {
+                    break ChainEnd::Loop;
+                }
+                visited.push(link.source.clone());
+                match db.find_by_target(&link.source) {
+                    Some(next) => link = next,
+                    None => break if link.source.exists() { ChainEnd::Resolved } else { ChainEnd::Broken },
+                }
+            };
+            match end {
+                ChainEnd::Resolved => println!("{}: {}", end, link.source.canonicalize()?.display()),
+                ChainEnd::Broken => println!("{}: {} does not exist", end, link.source.display()),
+                ChainEnd::Loop => println!("{}: {} is reached again", end, link.source.display()),
+            }
+            if end != ChainEnd::Resolved {
+                Failure::Error.exit();
+            }
+        }
+        Commands::Annotate { target, note } => {
+            match db.find_by_target(&absolute_path(&target, &base)) {
+                Some(mut link) => {
+                    link.note = Some(note).filter(|note| !note.is_empty());
+                    if !dry_run {
+                        db.save_quicklink(&link)?;
+                    }
+                    println!("{}Annotated link: {}", dry_run_prefix(dry_run), link);
+                },
+                None => {
+                    eprintln!("No tracked link found for target: {}", target.display());
+                    Failure::NotFound.exit();
+                }
+            }
+        }
+        #[cfg(unix)]
+        Commands::Serve { socket } => {
+            serve::serve(&db, &socket, &base)?;
+        }
+        Commands::Watch => {
+            watch::watch(&db)?;
+        }
+        Commands::Inspect { target, by_id, format } => {
+            match find_link(&db, target.as_deref(), by_id.as_deref(), None, &base) {
+                Ok(link) => {
+                    let fields = link.inspect();
+                    let live_metadata = link.target.symlink_metadata().ok()
+                        .and_then(|meta| platform::file_mode(&meta).zip(platform::file_id(&meta).map(|(_, inode)| inode)));
+                    match format {
+                        OutputFormat::Json => {
+                            let fields: Vec<serde_json::Value> = fields.iter().map(|(name, recorded, live)| serde_json::json!({
+                                "field": name, "recorded": recorded, "live": live, "differs": recorded != live,
+                            })).collect();
+                            let metadata = live_metadata.map(|(mode, inode)| serde_json::json!({ "mode": mode, "inode": inode }));
+                            println!("{}", serde_json::json!({ "id": db.record_id(&link), "fields": fields, "live_metadata": metadata }));
+                        },
+                        OutputFormat::Text => {
+                            println!("{:<8} {:<40} Live", "Field", "Recorded");
+                            for (name, recorded, live) in &fields {
+                                let marker = if recorded != live { "  <- differs" } else { "" };
+                                println!("{:<8} {:<40} {}{}", name, recorded, live, marker);
+                            }
+                            if let Some((mode, inode)) = live_metadata {
+                                println!("Live target mode: {}, inode: {} (not recorded)", mode, inode);
+                            }
+                        },
+                    }
+                },
+                Err(message) => {
+                    eprintln!("{}", message);
+                    Failure::NotFound.exit();
+                }
+            }
+        }
+        Commands::RemoveSession { session } => {
+            let links: Vec<QuickLink> = db.get_all().into_iter().filter(|link| link.session_id.as_deref() == Some(session.as_str())).collect();
+            if links.is_empty() {
+                eprintln!("No tracked links were created in session {}", session);
+                Failure::NotFound.exit();
+            }
+            let mut declined = 0;
+            for mut link in links {
+                if dry_run {
+                    println!("{}Link removed: {}", dry_run_prefix(dry_run), link);
+                    continue;
+                }
+                if !yes && !confirm(&format!("Remove link target {}?", link.target.display())) {
+                    declined += 1;
+                    eprintln!("Not removing {}: not confirmed", link.target.display());
+                    continue;
+                }
+                if link.is_in_place() {
+                    link.unlink()?;
+                }
+                remove_empty_dirs(&link.created_dirs);
+                db.remove_quicklink(&link)?;
+                println!("Link removed: {}", link);
+            }
+            if declined > 0 {
+                Failure::Error.exit();
+            }
+        }
+        Commands::Prune => {
+            let stale: Vec<QuickLink> = db.get_all().into_iter().filter(|link| !link.deferred && !link.source.exists()).collect();
+            let mut declined = 0;
+            for mut link in stale {
+                if dry_run {
+                    println!("{}Link removed: {}", dry_run_prefix(dry_run), link);
+                    continue;
+                }
+                if !yes && !confirm(&format!("Remove link target {}?", link.target.display())) {
+                    declined += 1;
+                    eprintln!("Not removing {}: not confirmed", link.target.display());
+                    continue;
+                }
+                if link.exists && link.is_in_place() {
+                    link.unlink()?;
+                }
+                remove_empty_dirs(&link.created_dirs);
+                db.remove_quicklink(&link)?;
+                println!("Link removed: {}", link);
+            }
+            if declined > 0 {
+                Failure::Error.exit();
+            }
+        }
+        Commands::Backup { file } => {
+            db.export_all(BufWriter::new(File::create(&file)?))?;
+            println!("Database backed up to {}", file.display());
+        }
+        Commands::Compact => {
+            let rewritten = if dry_run { db.get_all().len() } else { db.compact()? };
+            println!("{}Rewrote {} records", dry_run_prefix(dry_run), rewritten);
+        }
+        Commands::Undo => {
+            let journal = db.journal();
+            let Some(entry) = journal.last()? else {
+                eprintln!("Nothing to undo");
+                Failure::NotFound.exit();
+            };
+            if !dry_run {
+                undo(&db, &entry)?;
+                journal.pop()?;
+            }
+            println!("{}Undone: {}", dry_run_prefix(dry_run), entry);
+        }
+        Commands::Doctor => {
+            // Each problem with the suggested fix
+            let mut problems: Vec<(String, String)> = Vec::new();
+            if let Err(e) = db.check_writable() {
+                problems.push((format!("Records cannot be saved in {}: {}", db.root().join(".fslink/links").display(), e), "fix the folder's permissions".to_string()));
+            }
+            for (path, reason) in db.orphan_records() {
+                let fix = match reason {
+                    OrphanReason::Unreachable => "run `rehash` to move it to its id",
+                    OrphanReason::Empty | OrphanReason::Invalid => "run `gc --force` to delete it",
+                };
+                problems.push((format!("Orphan file {} ({})", path.display(), reason), fix.to_string()));
+            }
+            let mut by_target: HashMap<PathBuf, Vec<String>> = HashMap::new();
+            for (id, link) in db.get_all_with_ids() {
+                // Path equality skips `.` components, so compare the stored bytes the record id is hashed from
+                if normalize_path(&link.source).as_os_str() != link.source.as_os_str() || normalize_path(&link.target).as_os_str() != link.target.as_os_str() {
+                    problems.push((format!("Record {} stores paths that are not normalized: {}", id, link), "run `rehash` to normalize them".to_string()));
+                }
+                if !link.deferred && link.source.symlink_metadata().is_err() {
+                    problems.push((format!("Source of {} no longer exists", link), "run `prune` to drop the link".to_string()));
+                }
+                by_target.entry(normalize_path(&link.target)).or_default().push(id);
+            }
+            let mut duplicates: Vec<(PathBuf, Vec<String>)> = by_target.into_iter().filter(|(_, ids)| ids.len() > 1).collect();
+            duplicates.sort();
+            for (target, ids) in duplicates {
+                problems.push((format!("{} records share the target {}: {}", ids.len(), target.display(), ids.join(", ")), "keep one and remove the others with `remove --by-id`".to_string()));
+            }
+            for (problem, fix) in &problems {
+                println!("{}", problem);
+                println!("  Fix: {}", fix);
+            }
+            if !problems.is_empty() {
+                eprintln!("{} problems found in {}", problems.len(), db.root().join(".fslink").display());
+                Failure::Error.exit();
+            }
+            println!("No problems found in {}", db.root().join(".fslink").display());
+        }
+        Commands::Rehash => {
+            let (moved, merged) = db.rehash(dry_run)?;
+            for link in &merged {
+                println!("{}Merged duplicate record: {}", dry_run_prefix(dry_run), link);
+            }
+            println!("{}{} records moved to their current id, {} duplicates merged", dry_run_prefix(dry_run), moved, merged.len());
+        }
+        Commands::Gc { force } => {
+            let orphans = db.orphan_records();
+            let mut removed = 0;
+            for (path, reason) in &orphans {
+                if !force {
+                    println!("Orphan file: {} ({})", path.display(), reason);
+                    continue;
+                }
+                if !dry_run {
+                    std::fs::remove_file(path)?;
+                }
+                removed += 1;
+                println!("{}Removed orphan file: {} ({})", dry_run_prefix(dry_run), path.display(), reason);
+            }
+            if removed > 0 && !dry_run {
+                db.reindex()?;
+            }
+            println!("{}{} orphan files found, {} removed", dry_run_prefix(dry_run), orphans.len(), removed);
+        }
+        Commands::Restore { file, strategy, apply } => {
+            match db.import_all(BufReader::new(File::open(&file)?), strategy, dry_run) {
+                Ok((restored, skipped)) => {
+                    println!("{}Restored {} records, skipped {} existing", dry_run_prefix(dry_run), restored.len(), skipped);
+                    if apply && !repair_links(&db, restored, dry_run)? {
+                        Failure::Error.exit();
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Error restoring from {}: {}", file.display(), e);
+                    Failure::Error.exit();
+                }
+            }
+        }
+    }
+    output.finish();
+    Ok(())
+}
+
+/// Find a tracked link by its target path (resolved against `base`), its record id or its name.
+/// The error is a message naming what was looked for.
+fn find_link(db: &LinkStorage, target: Option<&Path>, id: Option<&str>, name: Option<&str>, base: &Path) -> Result<QuickLink, String> {
+    match (target, id, name) {
+        (_, Some(id), _) => db.get_by_id(id).ok_or_else(|| format!("No tracked link found with id: {}", id)),
+        (_, None, Some(name)) => db.find_by_name(name).map_err(|e| e.to_string())?.ok_or_else(|| format!("No tracked link found with name: {}", name)),
+        (Some(target), None, None) => db.find_by_target(&absolute_path(target, base)).ok_or_else(|| format!("No tracked link found for target: {}", target.display())),
+        (None, None, None) => Err("No target, id or name given".to_string()),
+    }
+}
+
+/// JSON representation of a link, including its record id
+fn link_json(db: &LinkStorage, link: &QuickLink) -> serde_json::Value {
+    let mut value = serde_json::to_value(link).unwrap();
+    value["id"] = db.record_id(link).into();
+    value
+}
+
+/// How many symlinks are followed before a chain is considered a loop, like the kernel's limit
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Where following a chain of symlinks ended up.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ChainEnd {
+    /// Reached an existing file or directory
+    Resolved,
+    /// Reached a path that does not exist
+    Broken,
+    /// Gave up after MAX_SYMLINK_HOPS symlinks
+    Loop,
+}
+
+impl Display for ChainEnd {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ChainEnd::Resolved => write!(f, "RESOLVED"),
+            ChainEnd::Broken => write!(f, "BROKEN CHAIN"),
+            ChainEnd::Loop => write!(f, "LOOP"),
+        }
+    }
+}
+
+/// Follow a chain of symlinks starting at `start`, returning every path visited and how the chain ended.
+fn follow_symlink_chain(start: &Path) -> (Vec<PathBuf>, ChainEnd) {
+    let mut chain = vec![start.to_path_buf()];
+    let mut current = start.to_path_buf();
+    while let Ok(destination) = read_link(&current) {
+        if chain.len() > MAX_SYMLINK_HOPS {
+            return (chain, ChainEnd::Loop);
+        }
+        current = absolute_path(&destination, current.parent().unwrap_or(Path::new("/")));
+        chain.push(current.clone());
+    }
+    let end = if current.symlink_metadata().is_ok() { ChainEnd::Resolved } else { ChainEnd::Broken };
+    (chain, end)
+}
+
+/// The chain of tracked links a new link from `source` to `target` would close into a cycle, if any.
+/// Starting at `source`, each path is resolved through the tracked softlink whose target it is or lies inside,
+/// until it reaches `target` (a cycle) or a path no tracked link covers. Returns the paths visited, ending at `target` or a path inside it.
+fn find_link_cycle(db: &LinkStorage, source: &Path, target: &Path) -> Option<Vec<PathBuf>> {
+    let softlinks = db.softlinks();
+    let mut chain = vec![source.to_path_buf()];
+    let mut current = source.to_path_buf();
+    loop {
+        if current.starts_with(target) {
+            return Some(chain);
+        }
+        // The innermost tracked softlink the path goes through
+        let (covering, covering_source) = current.ancestors().find_map(|ancestor| softlinks.get_key_value(ancestor))?;
+        let inside = current.strip_prefix(covering).unwrap();
+        current = if inside.as_os_str().is_empty() { covering_source.clone() } else { covering_source.join(inside) };
+        if chain.contains(&current) {
+            return None; // An existing cycle that does not pass through the new link
+        }
+        chain.push(current.clone());
+    }
+}
+
+/// Current time in unix seconds
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// A duration in seconds in its largest whole unit, e.g. `3d` or `12m`
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m", seconds / 60),
+        3600..86400 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+/// Id of this invocation, stamped on every link it creates: the start time and process id in hex.
+fn session_id() -> &'static str {
+    static SESSION_ID: OnceLock<String> = OnceLock::new();
+    SESSION_ID.get_or_init(|| {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!("{:x}-{:x}", started.as_secs(), std::process::id())
+    })
+}
+
+/// Whether two files have identical content, comparing sizes first
+fn same_content(first: &Path, second: &Path) -> bool {
+    let (Ok(first_meta), Ok(second_meta)) = (std::fs::metadata(first), std::fs::metadata(second)) else {
+        return false;
+    };
+    if !first_meta.is_file() || !second_meta.is_file() || first_meta.len() != second_meta.len() {
+        return false;
+    }
+    let (Ok(first_file), Ok(second_file)) = (File::open(first), File::open(second)) else {
+        return false;
+    };
+    let (mut first_reader, mut second_reader) = (BufReader::new(first_file), BufReader::new(second_file));
+    loop {
+        let (Ok(first_chunk), Ok(second_chunk)) = (first_reader.fill_buf(), second_reader.fill_buf()) else {
+            return false;
+        };
+        let length = first_chunk.len().min(second_chunk.len());
+        if length == 0 {
+            return first_chunk.len() == second_chunk.len();
+        }
+        if first_chunk[..length] != second_chunk[..length] {
+            return false;
+        }
+        first_reader.consume(length);
+        second_reader.consume(length);
+    }
+}
+
+/// Share of a filesystem's free inodes a bulk operation may use before it is warned about
+const INODE_SAFETY_FRACTION: f64 = 0.5;
+
+/// Warn when the `planned` (source, target) links would use up more than a safe share of the free inodes on the
+/// filesystems of their target directories. Returns false if that happens and `strict` is set.
+/// Every planned link is counted as one inode, which over-estimates hardlinks.
+fn check_free_inodes(planned: &[(PathBuf, PathBuf)], strict: bool) -> bool {
+    let mut per_directory: HashMap<&Path, usize> = HashMap::new();
+    for (_, target) in planned {
+        *per_directory.entry(target.parent().unwrap_or(target)).or_default() += 1;
+    }
+    let mut per_filesystem = HashMap::new();
+    for (dir, links) in per_directory {
+        // Directories a --recursive mirror has yet to create are on the filesystem of their nearest existing ancestor
+        let Some(existing) = dir.ancestors().find(|ancestor| ancestor.is_dir()) else {
+            continue;
+        };
+        if let Some((filesystem, files, files_available)) = platform::inode_counts(existing) && files > 0 { // Some filesystems do not report inode counts
+            per_filesystem.entry(filesystem).or_insert((existing, files_available, 0)).2 += links;
+        }
+    }
+    let mut acceptable = true;
+    for (dir, free_inodes, links) in per_filesystem.values() {
+        if *links as f64 > *free_inodes as f64 * INODE_SAFETY_FRACTION {
+            let level = if strict { "Error" } else { "Warning" };
+            eprintln!("{}: {} links are planned on the filesystem of {}, which has only {} free inodes", level, links, dir.display(), free_inodes);
+            acceptable &= !strict;
+        }
+    }
+    acceptable
+}
+
+/// Options of a Create invocation that apply to every link it makes.
+#[derive(Default)]
+struct CreateOptions {
+    /// Arguments to record on each created link
+    origin_args: Option<Vec<String>>,
+    /// Check whether the link shadows a command in $PATH
+    warn_shadow: bool,
+    /// Turn warnings into errors
+    strict: bool,
+    /// Converge an existing target to the requested link instead of refusing it
+    replace_if_different: bool,
+    /// Record links with missing sources as deferred instead of refusing them
+    allow_missing_source: bool,
+    /// OS/host conditions to record on each link
+    only_on: Vec<String>,
+    /// Point softlinks to their source by a path relative to the link
+    relative: bool,
+    /// Report what would be done without touching the filesystem or the database
+    dry_run: bool,
+    /// Tags to record on each link
+    tags: Vec<String>,
+    /// Move an existing file or directory at the target aside instead of refusing it
+    backup: bool,
+    /// Replace an existing file or differing symlink at the target
+    force: bool,
+    /// Replace without asking for confirmation (the global --yes)
+    yes: bool,
+    /// Name to record on the link, unique among tracked links
+    name: Option<String>,
+    /// Give softlinks their source's times
+    preserve: bool,
+    /// Note to record on each link
+    note: Option<String>,
+    /// Create a softlink where a hardlink fails because source and target are on different filesystems
+    fallback_symlink: bool,
+}
+
+/// What Create did for a single target.
+enum CreateOutcome {
+    /// A new link was made and saved
+    Created(QuickLink),
+    /// The target already was the requested link
+    Unchanged(QuickLink),
+    /// The link was saved but not made, because its --only-on conditions do not match this machine
+    Skipped(QuickLink),
+}
+
+/// Create, link and save a single QuickLink, refusing source/target pairs that are already tracked.
+/// Both paths must already be absolute, and `created_dirs` are the directories made for it, recorded on the link. Everything is checked before an existing target is moved out of the way,
+/// and what was moved is put back if the link then cannot be created.
+fn create_link(db: &LinkStorage, abs_source: &Path, abs_target: &Path, link_type: LinkType, options: &CreateOptions, created_dirs: &[PathBuf]) -> Result<CreateOutcome, QuickLinkCreationError> {
+    let prefix = dry_run_prefix(options.dry_run);
+    // The tracked link at the target that --replace-if-different converges to the requested one
+    let mut replaced = db.find_by_target(abs_target).filter(|_| options.replace_if_different);
+    if let Some(existing) = &replaced && existing.source == abs_source && existing.linktype == link_type && existing.is_in_place() {
+        return Ok(CreateOutcome::Unchanged(existing.clone()));
+    }
+    let is_replaced = |link: &QuickLink| replaced.as_ref().is_some_and(|existing| existing.source == link.source && existing.target == link.target);
+    if db.get_quicklink(abs_source, abs_target).is_some_and(|tracked| !is_replaced(&tracked)) {
+        return Err(QuickLinkCreationError::AlreadyTracked(abs_source.to_string_lossy().into_owned(), abs_target.to_string_lossy().into_owned()));
+    }
+    if link_type == LinkType::Softlink && let Some(chain) = find_link_cycle(db, abs_source, abs_target) {
+        return Err(QuickLinkCreationError::WouldCreateCycle(abs_target.to_string_lossy().into_owned(), chain.iter().map(|path| path.display().to_string()).collect()));
+    }
+    if let Some(name) = &options.name && let Some(named) = db.find_by_name(name)? && !is_replaced(&named) {
+        return Err(QuickLinkCreationError::NameTaken(name.clone(), named.target.to_string_lossy().into_owned()));
+    }
+    if options.warn_shadow {
+        for command in shadowed_commands(abs_target) {
+            if options.strict {
+                return Err(QuickLinkCreationError::ShadowsCommand(abs_target.to_string_lossy().into_owned(), command.to_string_lossy().into_owned()));
+            }
+            eprintln!("Warning: {} will shadow {} in $PATH", abs_target.display(), command.display());
+        }
+    }
+    let deferred = options.allow_missing_source && !abs_source.exists();
+    if options.replace_if_different && !deferred {
+        QuickLink::check_source(abs_source, link_type)?;
+    }
+    let mut backup = None;
+    // Whether the replaced link was unlinked, so it is linked again if the new one cannot be created
+    let mut unlinked = false;
+    if let Some(existing) = &mut replaced {
+        if existing.is_in_place() {
+            if !options.dry_run {
+                existing.unlink_forced()?;
+            }
+            unlinked = true;
+            println!("{}Retargeting {} from {}", prefix, abs_target.display(), existing.source.display());
+        }
+        if !options.dry_run && let Err(e) = db.remove_quicklink(existing) {
+            put_back(db, abs_target, None, Some(existing), unlinked)?;
+            return Err(e.into());
+        }
+    }
+    // On a dry run, the unlinked link is still there but would have been removed
+    if options.replace_if_different && abs_target.symlink_metadata().is_ok() && !(options.dry_run && unlinked) {
+        let backup_target = backup_path(abs_target);
+        if !options.dry_run && let Err(e) = std::fs::rename(abs_target, &backup_target) {
+            put_back(db, abs_target, None, replaced.as_mut(), unlinked)?;
+            return Err(e.into());
+        }
+        backup = Some(backup_target);
+    }
+    // A hardlink already at the target is imported rather than replaced
+    let adopted = link_type == LinkType::Hardlink && same_inode(abs_source, abs_target);
+    // Directories are never forced out of the way, only moved aside with --backup
+    let forced = options.force && !adopted && abs_source.symlink_metadata().is_ok() && abs_target.symlink_metadata().is_ok_and(|meta| {
+        if meta.is_symlink() {
+            link_type == LinkType::Hardlink || read_link(abs_target).ok() != abs_source.canonicalize().ok()
+        } else {
+            !meta.is_dir()
+        }
+    }) && (options.yes || options.dry_run || confirm(&format!("Replace existing {}?", abs_target.display())));
+    if options.backup && backup.is_none() && !adopted && (forced || abs_target.symlink_metadata().is_ok_and(|meta| !meta.is_symlink())) {
+        let backup_target = backup_path(abs_target);
+        if !options.dry_run {
+            std::fs::rename(abs_target, &backup_target)?;
+        }
+        backup = Some(backup_target);
+    } else if forced {
+        if !options.dry_run {
+            std::fs::remove_file(abs_target)?;
+        }
+        println!("{}Removed existing {}", prefix, abs_target.display());
+    }
+    let created = if deferred {
+        QuickLink::new_deferred(abs_source, abs_target, link_type)
+    } else if options.dry_run && (replaced.is_some() || backup.is_some() || forced) {
+        // The target was only moved aside on paper, so it cannot be checked against
+        Ok(QuickLink { source: abs_source.to_path_buf(), target: abs_target.to_path_buf(), linktype: link_type, ..Default::default() })
+    } else {
+        QuickLink::new(abs_source, abs_target, link_type)
+    };
+    let mut link = match created {
+        Ok(link) => link,
+        Err(e) => {
+            if !options.dry_run {
+                put_back(db, abs_target, backup.as_deref(), replaced.as_mut(), unlinked)?;
+            }
+            return Err(e);
+        }
+    };
+    if let Some(backup) = &backup {
+        println!("{}Moved existing {} to {}", prefix, abs_target.display(), backup.display());
+    }
+    link.origin_args = options.origin_args.clone();
+    link.session_id = Some(session_id().to_string());
+    link.only_on = options.only_on.clone();
+    link.tags = options.tags.clone();
+    link.name = options.name.clone();
+    link.note = options.note.clone();
+    link.backup = backup;
+    link.created_dirs = created_dirs.to_vec();
+    link.relative = options.relative && link.linktype == LinkType::Softlink;
+    link.preserve = options.preserve && link.linktype == LinkType::Softlink;
+    let applies = link.applies_here();
+    if applies && !link.deferred && !link.exists {
+        // A link already at the target was imported by QuickLink::new
+        if options.dry_run {
+            link.exists = true;
+        } else if let Err(e) = link_or_fall_back(db, &mut link, options) {
+            put_back(db, abs_target, link.backup.as_deref(), replaced.as_mut(), unlinked)?;
+            return Err(e);
+        }
+    }
+    if !options.dry_run {
+        db.save_quicklink(&link)?;
+        db.journal().push(&JournalEntry::Unlink { source: link.source.clone(), target: link.target.clone() })?;
     }
+    Ok(if applies { CreateOutcome::Created(link) } else { CreateOutcome::Skipped(link) })
 }
-mod database;
-mod cli;
 
-use std::{env, error::Error, fmt::{self, Debug, Display, Formatter}, io, os::unix::fs, path::{Path, PathBuf}};
-use clap::{Parser, ValueEnum};
-use std::fs::read_link;
-use serde::{Deserialize, Serialize};
+/// Put back what create_link moved out of the way of a link it then could not create: the file moved to `backup`,
+/// and the tracked link it was replacing, which is linked again if it was `unlinked`.
+fn put_back(db: &LinkStorage, target: &Path, backup: Option<&Path>, replaced: Option<&mut QuickLink>, unlinked: bool) -> io::Result<()> {
+    if let Some(backup) = backup {
+        std::fs::rename(backup, target)?;
+    }
+    if let Some(replaced) = replaced {
+        if unlinked {
+            replaced.link()?;
+        }
+        db.save_quicklink(replaced)?;
+    }
+    Ok(())
+}
 
-use crate::database::LinkStorage;
-use crate::cli::{Cli, Commands};
+/// Link `link`, with --fallback-symlink creating a softlink instead when a hardlink would cross filesystems.
+/// The softlink is checked for cycles like any other, since the hardlink was not.
+fn link_or_fall_back(db: &LinkStorage, link: &mut QuickLink, options: &CreateOptions) -> Result<(), QuickLinkCreationError> {
+    let Err(e) = link.link() else {
+        return Ok(());
+    };
+    if link.linktype != LinkType::Hardlink || !platform::is_cross_device_error(&e) {
+        return Err(e.into());
+    }
+    if !options.fallback_symlink {
+        return Err(QuickLinkCreationError::CrossesFilesystems(link.source.to_string_lossy().into_owned(), link.target.to_string_lossy().into_owned()));
+    }
+    if let Some(chain) = find_link_cycle(db, &link.source, &link.target) {
+        return Err(QuickLinkCreationError::WouldCreateCycle(link.target.to_string_lossy().into_owned(), chain.iter().map(|path| path.display().to_string()).collect()));
+    }
+    println!("Cannot hardlink {} across filesystems, creating a softlink instead", link.target.display());
+    link.linktype = LinkType::Softlink;
+    link.relative = options.relative;
+    link.preserve = options.preserve;
+    Ok(link.link()?)
+}
 
-fn main() -> std::io::Result<()> {
-    let cli = Cli::parse();
-    let db = LinkStorage::init(&env::current_dir().unwrap());
+/// Result of one operation on a link, printed as a JSON line with --json
+#[derive(Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum CommandResult {
+    /// The operation changed the link, or would have on a dry run
+    Done { operation: &'static str, dry_run: bool, link: serde_json::Value },
+    /// The link already was as requested
+    Unchanged { operation: &'static str, link: serde_json::Value },
+    /// The link's current state
+    State { state: String, link: serde_json::Value },
+    /// The operation failed
+    Error { operation: &'static str, error: String, #[serde(skip)] failure: Failure },
+}
 
-    match cli.command {
-        Commands::Create { source, target, link_type } => {
-            let abs_source = absolute_path(&source);
-            let abs_target = absolute_path(&target);
-            let already_exists = db.get_quicklink(abs_source.to_str().unwrap(), abs_target.to_str().unwrap()).is_some();
-            if already_exists {
-                eprintln!("A link for source '{}' and target '{}' already exists in the database.", abs_source.display(), abs_target.display());
-                return Ok(());
-            }
-            let quicklink = QuickLink::new(&source, &target, link_type);
-            match quicklink {
-                Ok(mut link) => {
-                    link.link()?;
-                    db.save_quicklink(&link);
-                    println!("Link created: {}", link);
-                },
-                Err(e) => {
-                    eprintln!("Error creating link: {}", e);
-                }
-            }
+/// Where Create, Remove, Toggle and Status report their results: human text, or JSON lines with --json
+struct Output {
+    json: bool,
+    /// Category of the first error reported
+    failed: Option<Failure>,
+}
+
+impl Output {
+    /// Report a result, as JSON or as `text` (on stderr for errors)
+    fn emit(&mut self, result: CommandResult, text: impl Display) {
+        if let CommandResult::Error { failure, .. } = &result {
+            self.failed = self.failed.or(Some(*failure));
         }
-        Commands::Remove { target } => {
-            match db.find_by_target(&target) {
-                Some(mut link) => {
-                    if link.exists {
-                        link.unlink()?;
-                        println!("Link removed: {}", link);
-                    } else {
-                        println!("Link not present in filesystem: {}", link);
-                    }
-                },
-                None => {
-                    eprintln!("No tracked link found for target: {}", target.display());
-                }
+        let is_error = matches!(result, CommandResult::Error { .. });
+        match (self.json, is_error) {
+            (true, _) => println!("{}", serde_json::to_string(&result).unwrap()),
+            (false, false) => println!("{}", text),
+            (false, true) => eprintln!("{}", text),
+        }
+    }
+
+    /// Print a summary line, which JSON output leaves out
+    fn note(&self, text: impl Display) {
+        if !self.json {
+            println!("{}", text);
+        }
+    }
+
+    /// Exit with the code of the first reported error, if any
+    fn finish(&self) {
+        if let Some(failure) = self.failed {
+            failure.exit();
+        }
+    }
+}
+
+/// Exit codes of failed runs, by category of error. Invalid arguments exit with 2, from clap.
+#[derive(Clone, Copy, Debug)]
+enum Failure {
+    /// An operation failed
+    Error = 1,
+    /// No database, tracked link or source was found
+    NotFound = 3,
+    /// Something already exists where the requested link or database would go
+    Conflict = 4,
+}
+
+impl Failure {
+    fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+
+    fn of_storage(error: &StorageError) -> Failure {
+        match error {
+            StorageError::NoDatabaseFound => Failure::NotFound,
+            StorageError::DatabaseExists(..) => Failure::Conflict,
+            _ => Failure::Error,
+        }
+    }
+}
+
+/// Prefix for messages describing a change that --dry-run only reports
+fn dry_run_prefix(dry_run: bool) -> &'static str {
+    if dry_run { "[dry-run] " } else { "" }
+}
+
+/// The glob pattern to expand if `source` contains glob metacharacters, made absolute against `base`
+fn source_glob(source: &Path, base: &Path) -> Option<String> {
+    let source = source.to_string_lossy();
+    if !source.contains(['*', '?', '[']) {
+        return None;
+    }
+    if Path::new(source.as_ref()).is_absolute() {
+        Some(source.into_owned())
+    } else {
+        Some(format!("{}/{}", glob::Pattern::escape(&base.to_string_lossy()), source))
+    }
+}
+
+/// Create `dir` and its missing parents, returning the directories created, innermost first. With a `mirror` of
+/// (source root, target root), each directory created under the target root gets the permissions of its counterpart under the source root.
+fn create_mirrored_dirs(dir: &Path, mirror: Option<&(PathBuf, PathBuf)>) -> io::Result<Vec<PathBuf>> {
+    let missing: Vec<PathBuf> = dir.ancestors()
+        .take_while(|ancestor| ancestor.symlink_metadata().is_err())
+        .map(Path::to_path_buf)
+        .collect();
+    std::fs::create_dir_all(dir)?;
+    if let Some((source_root, target_root)) = mirror {
+        for created in &missing {
+            if let Ok(relative) = created.strip_prefix(target_root)
+                && let Ok(metadata) = std::fs::metadata(source_root.join(relative)) {
+                std::fs::set_permissions(created, metadata.permissions())?;
             }
         }
-        Commands::List => {
-            let links = db.get_all();
-            println!("Tracked links:");
-            for link in links {
-                println!("{}", link);
+    }
+    Ok(missing)
+}
+
+/// Remove those of `dirs` that are empty, in order, so an innermost directory goes before its parents
+fn remove_empty_dirs(dirs: &[PathBuf]) {
+    for dir in dirs {
+        // Fails on a directory that is not empty or already gone, which is left alone
+        std::fs::remove_dir(dir).ok();
+    }
+}
+
+/// Every non-directory entry under `dir`, recursively. Symlinks inside are listed, not followed.
+fn source_tree_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            files.extend(source_tree_files(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// A link of an all-or-nothing batch that failed, after the links created before it were rolled back
+struct BatchFailure {
+    target: PathBuf,
+    error: QuickLinkCreationError,
+    /// How many links created before it were rolled back
+    rolled_back: usize,
+}
+
+/// Create every planned (source, target) link with `create`, reporting each on `output`, and return how many are in place.
+/// With `keep_partial`, failures are reported and the rest are still created. Otherwise the batch is all or nothing:
+/// the first failure stops it, and the links created so far are unlinked and their records removed again.
+fn apply_all(db: &LinkStorage, planned: &[(PathBuf, PathBuf)], keep_partial: bool, dry_run: bool, output: &mut Output,
+             mut create: impl FnMut(&Path, &Path) -> Result<CreateOutcome, QuickLinkCreationError>) -> Result<usize, BatchFailure> {
+    let mut linked = 0;
+    let mut created = Vec::new();
+    for (source, target) in planned {
+        match create(source, target) {
+            Ok(CreateOutcome::Created(link)) => {
+                linked += 1;
+                output.emit(CommandResult::Done { operation: "create", dry_run, link: link_json(db, &link) },
+                    format!("{}Link created: {}", dry_run_prefix(dry_run), link));
+                created.push(link);
+            },
+            Ok(CreateOutcome::Unchanged(link)) => {
+                linked += 1;
+                output.emit(CommandResult::Unchanged { operation: "create", link: link_json(db, &link) },
+                    format!("Link already in place: {}", link));
+            },
+            Ok(CreateOutcome::Skipped(link)) => {
+                output.emit(CommandResult::Done { operation: "create", dry_run, link: link_json(db, &link) },
+                    format!("{}Link recorded but not linked, its conditions do not match this machine: {}", dry_run_prefix(dry_run), link));
+                created.push(link);
+            },
+            Err(error) if !keep_partial => {
+                let rolled_back = if dry_run { created.len() } else { roll_back(db, &created) };
+                return Err(BatchFailure { target: target.clone(), error, rolled_back });
+            },
+            Err(e) => output.emit(CommandResult::Error { operation: "create", error: format!("{}: {}", target.display(), e), failure: e.failure() },
+                format!("Error creating link at {}: {}", target.display(), e)),
+        }
+    }
+    Ok(linked)
+}
+
+/// Undo the creation of `created` links, newest first, dropping their journal entries, and return how many were undone.
+/// Links that cannot be undone are reported and left in place.
+fn roll_back(db: &LinkStorage, created: &[QuickLink]) -> usize {
+    let journal = db.journal();
+    let mut undone = 0;
+    for link in created.iter().rev() {
+        let entry = JournalEntry::Unlink { source: link.source.clone(), target: link.target.clone() };
+        if let Err(e) = undo(db, &entry) {
+            eprintln!("Error rolling back {}: {}", link.target.display(), e);
+            continue;
+        }
+        undone += 1;
+        if journal.last().ok().flatten().is_some_and(|last| last == entry) && let Err(e) = journal.pop() {
+            eprintln!("Error updating the journal: {}", e);
+        }
+    }
+    undone
+}
+
+/// Apply the inverse operation recorded in a journal entry. Links that were changed by hand since are
+/// left as they are where possible, e.g. a created link that was already deleted is only forgotten.
+fn undo(db: &LinkStorage, entry: &JournalEntry) -> io::Result<()> {
+    let (source, target) = match entry {
+        JournalEntry::Unlink { source, target } | JournalEntry::Relink { source, target, .. }
+            | JournalEntry::Toggle { source, target } | JournalEntry::Move { source, target, .. } => (source, target),
+    };
+    let removed = match entry {
+        JournalEntry::Relink { record, .. } => record.as_deref().cloned(),
+        _ => None,
+    };
+    let Some(mut link) = db.get_quicklink(source, target).or(removed) else {
+        if let JournalEntry::Unlink { .. } = entry {
+            return Ok(());
+        }
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no tracked link for {} -> {}", source.display(), target.display())));
+    };
+    match entry {
+        JournalEntry::Unlink { .. } => {
+            if link.is_in_place() {
+                link.unlink()?;
             }
+            if link.backup.is_some() && link.target.symlink_metadata().is_err() {
+                link.restore_backup()?;
+            }
+            remove_empty_dirs(&link.created_dirs);
+            db.remove_quicklink(&link)?;
+        },
+        JournalEntry::Relink { .. } => {
+            if !link.is_in_place() {
+                link.link()?;
+            }
+            db.save_quicklink(&link)?;
+        },
+        JournalEntry::Toggle { .. } => {
+            link.toggle_link()?;
+            db.save_quicklink(&link)?;
+        },
+        JournalEntry::Move { to, .. } => {
+            db.remove_quicklink(&link)?;
+            let moved = link.relocate(to);
+            db.save_quicklink(&link)?;
+            moved?;
+        },
+    }
+    Ok(())
+}
+
+/// Recreate those of `links` that are recorded as present but missing from the filesystem, and create the deferred
+/// ones whose source now exists, creating their parent directories as needed.
+/// Prints a summary, and returns false if any of them could not be recreated.
+fn repair_links(db: &LinkStorage, links: Vec<QuickLink>, dry_run: bool) -> io::Result<bool> {
+    let (mut repaired, mut failed) = (0, 0);
+    for mut link in links.into_iter().filter(QuickLink::applies_here) {
+        if !(link.exists || link.deferred) || link.target.symlink_metadata().is_ok() {
+            continue;
         }
-        Commands::Toggle { target } => {
-            match db.find_by_target(&target) {
-                Some(mut link) => {
-                    link.toggle_link()?;
-                    db.save_quicklink(&link);
-                    println!("Toggled link: {}", link);
-                },
-                None => {
-                    eprintln!("No tracked link found for target: {}", target.display());
-                }
+        if link.deferred && !link.source.exists() {
+            continue; // Still waiting for its source
+        }
+        if !link.source.exists() {
+            failed += 1;
+            eprintln!("Cannot repair, source does not exist: {}", link);
+            continue;
+        }
+        if dry_run {
+            repaired += 1;
+            println!("{}Link repaired: {}", dry_run_prefix(dry_run), link);
+            continue;
+        }
+        let parent_created = link.target.parent().map_or(Ok(()), std::fs::create_dir_all);
+        match parent_created.and_then(|()| link.link()) {
+            Ok(()) => {
+                repaired += 1;
+                db.save_quicklink(&link)?;
+                println!("Link repaired: {}", link);
+            },
+            Err(e) => {
+                failed += 1;
+                eprintln!("Error repairing {}: {}", link.target.display(), e);
             }
         }
     }
+    println!("{}{} links repaired, {} failed", dry_run_prefix(dry_run), repaired, failed);
+    Ok(failed == 0)
+}
+
+/// Move a removed link's backup back to its target, saving the link without it.
+fn restore_link_backup(db: &LinkStorage, link: &mut QuickLink, dry_run: bool, output: &Output) -> io::Result<()> {
+    let Some(backup) = link.backup.clone() else {
+        return Ok(());
+    };
+    if !dry_run {
+        link.restore_backup()?;
+        db.save_quicklink(link)?;
+    }
+    output.note(format!("{}Restored {} from {}", dry_run_prefix(dry_run), link.target.display(), backup.display()));
     Ok(())
 }
 
+/// Ask a yes/no question on the terminal, defaulting to no.
+/// Without a terminal to ask on, the answer is no rather than waiting for input that may never come.
+fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        eprintln!("{} Not confirmed: stdin is not a terminal, pass --yes to proceed", question);
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Whether both paths exist and are the same inode, i.e. hardlinks of each other. Symlinks are not followed.
+fn same_inode(a: &Path, b: &Path) -> bool {
+    match (std::fs::symlink_metadata(a), std::fs::symlink_metadata(b)) {
+        (Ok(a_meta), Ok(b_meta)) => platform::file_id(&a_meta).is_some_and(|id| platform::file_id(&b_meta) == Some(id)),
+        _ => false,
+    }
+}
+
+/// First free `<target>.bak`, `<target>.bak.1`, ... path to move an existing target aside to.
+fn backup_path(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push(".bak");
+    let mut candidate = PathBuf::from(&name);
+    let mut counter = 1;
+    while candidate.symlink_metadata().is_ok() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{}", counter));
+        candidate = PathBuf::from(numbered);
+        counter += 1;
+    }
+    candidate
+}
+
+/// Commands with the target's file name in $PATH directories that come after the target's directory.
+fn shadowed_commands(target: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(name), Some(path_var)) = (target.parent(), target.file_name(), env::var_os("PATH")) else {
+        return Vec::new();
+    };
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    env::split_paths(&path_var)
+        .map(|entry| entry.canonicalize().unwrap_or(entry))
+        .skip_while(|entry| *entry != dir)
+        .skip(1)
+        .map(|entry| entry.join(name))
+        .filter(|candidate| candidate.is_file())
+        .collect()
+}
 
 enum QuickLinkCreationError {
     /// Format: source
@@ -90,12 +1739,41 @@ enum QuickLinkCreationError {
     TargetLinkHasDifferentSource(String, String, String), 
     /// Format: source, linktype, targettype
     UnavailableLinkType(String, LinkType, FileType), 
+    /// Format: source, target
+    AlreadyTracked(String, String),
+    /// Format: target, shadowed command
+    ShadowsCommand(String, String),
+    /// Format: target
+    NotASymlink(String),
+    /// Format: name, target of the link already named so
+    NameTaken(String, String),
+    /// Format: target, paths from the source through tracked links back to the target
+    WouldCreateCycle(String, Vec<String>),
+    /// Format: source, target
+    CrossesFilesystems(String, String),
+    /// Format: source, target, link type it is tracked as
+    TrackedAsOtherType(String, String, LinkType),
+    /// The process is not allowed to create symlinks (Windows without Developer Mode or elevation)
+    SymlinkPrivilegeNotHeld,
     /// Format: io_error
     LinkIOError(io::Error)
 }
 
 impl Error for QuickLinkCreationError {}
 
+impl QuickLinkCreationError {
+    fn failure(&self) -> Failure {
+        match self {
+            QuickLinkCreationError::SourceDoesNotExist(..) | QuickLinkCreationError::NotASymlink(..) => Failure::NotFound,
+            QuickLinkCreationError::TargetExists(..) | QuickLinkCreationError::TargetLinkHasDifferentSource(..)
+                | QuickLinkCreationError::AlreadyTracked(..) | QuickLinkCreationError::ShadowsCommand(..)
+                | QuickLinkCreationError::NameTaken(..) | QuickLinkCreationError::WouldCreateCycle(..)
+                | QuickLinkCreationError::TrackedAsOtherType(..) => Failure::Conflict,
+            _ => Failure::Error,
+        }
+    }
+}
+
 impl Display for QuickLinkCreationError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
@@ -103,6 +1781,14 @@ impl Display for QuickLinkCreationError {
             QuickLinkCreationError::TargetExists(source_path, target_path) => write!(f, "Link for {} cannot be created - target ({}) exists", source_path, target_path),
             QuickLinkCreationError::TargetLinkHasDifferentSource(source_path, target_path, different_source) => write!(f, "Link for {} cannot be created - target ({}) is already a link from {}", source_path, target_path, different_source),
             QuickLinkCreationError::UnavailableLinkType(source_path, linktype, targettype) => write!(f, "Link for {} cannot be created - link type {} is incompatible with source type: {}", source_path, linktype, targettype),
+            QuickLinkCreationError::AlreadyTracked(source_path, target_path) => write!(f, "A link for source '{}' and target '{}' already exists in the database", source_path, target_path),
+            QuickLinkCreationError::ShadowsCommand(target_path, command_path) => write!(f, "Link {} cannot be created - it would shadow {} in $PATH", target_path, command_path),
+            QuickLinkCreationError::NotASymlink(target_path) => write!(f, "{} cannot be imported - it is not a symlink", target_path),
+            QuickLinkCreationError::NameTaken(name, target_path) => write!(f, "The name '{}' is already used by the link at {}", name, target_path),
+            QuickLinkCreationError::WouldCreateCycle(target_path, chain) => write!(f, "Link {} cannot be created - it would create a cycle: {} -> {}", target_path, target_path, chain.join(" -> ")),
+            QuickLinkCreationError::CrossesFilesystems(source_path, target_path) => write!(f, "Hardlink for {} cannot be created - target ({}) is on another filesystem, pass --fallback-symlink to create a softlink instead", source_path, target_path),
+            QuickLinkCreationError::TrackedAsOtherType(source_path, target_path, linktype) => write!(f, "The link for source '{}' and target '{}' is already tracked as a {}", source_path, target_path, linktype),
+            QuickLinkCreationError::SymlinkPrivilegeNotHeld => write!(f, "Symlinks cannot be created - enable Developer Mode or run from an elevated prompt"),
             QuickLinkCreationError::LinkIOError(ioerror) => write!(f, "Encountered an io error while linking: {}", ioerror),
         }
     }
@@ -115,6 +1801,14 @@ impl Debug for QuickLinkCreationError {
             QuickLinkCreationError::TargetExists(source_path, target_path) => write!(f, "Link for {} cannot be created - target ({}) exists", source_path, target_path),
             QuickLinkCreationError::TargetLinkHasDifferentSource(source_path, target_path, different_source) => write!(f, "Link for {} cannot be created - target ({}) is already a link from {}", source_path, target_path, different_source),
             QuickLinkCreationError::UnavailableLinkType(source_path, linktype, targettype) => write!(f, "Link for {} cannot be created - link type {} is incompatible with source type: {}", source_path, linktype, targettype),
+            QuickLinkCreationError::AlreadyTracked(source_path, target_path) => write!(f, "A link for source '{}' and target '{}' already exists in the database", source_path, target_path),
+            QuickLinkCreationError::ShadowsCommand(target_path, command_path) => write!(f, "Link {} cannot be created - it would shadow {} in $PATH", target_path, command_path),
+            QuickLinkCreationError::NotASymlink(target_path) => write!(f, "{} cannot be imported - it is not a symlink", target_path),
+            QuickLinkCreationError::NameTaken(name, target_path) => write!(f, "The name '{}' is already used by the link at {}", name, target_path),
+            QuickLinkCreationError::WouldCreateCycle(target_path, chain) => write!(f, "Link {} cannot be created - it would create a cycle: {} -> {}", target_path, target_path, chain.join(" -> ")),
+            QuickLinkCreationError::CrossesFilesystems(source_path, target_path) => write!(f, "Hardlink for {} cannot be created - target ({}) is on another filesystem, pass --fallback-symlink to create a softlink instead", source_path, target_path),
+            QuickLinkCreationError::TrackedAsOtherType(source_path, target_path, linktype) => write!(f, "The link for source '{}' and target '{}' is already tracked as a {}", source_path, target_path, linktype),
+            QuickLinkCreationError::SymlinkPrivilegeNotHeld => write!(f, "Symlinks cannot be created - enable Developer Mode or run from an elevated prompt"),
             QuickLinkCreationError::LinkIOError(ioerror) => write!(f, "Encountered an io error while linking: {}", ioerror),
         }
     }
@@ -122,14 +1816,61 @@ impl Debug for QuickLinkCreationError {
 
 impl From<std::io::Error> for QuickLinkCreationError {
     fn from(value: std::io::Error) -> Self {
+        if platform::is_symlink_privilege_error(&value) {
+            return QuickLinkCreationError::SymlinkPrivilegeNotHeld;
+        }
         QuickLinkCreationError::LinkIOError(value)
     }
 }
 
+enum UnlinkError {
+    /// Format: target, source
+    NotTheTrackedLink(String, String),
+    /// Format: io_error
+    UnlinkIOError(io::Error)
+}
+
+impl Error for UnlinkError {}
+
+impl Display for UnlinkError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            UnlinkError::NotTheTrackedLink(target_path, source_path) => write!(f, "{} is no longer the tracked link to {}", target_path, source_path),
+            UnlinkError::UnlinkIOError(ioerror) => write!(f, "Encountered an io error while unlinking: {}", ioerror),
+        }
+    }
+}
+
+impl Debug for UnlinkError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            UnlinkError::NotTheTrackedLink(target_path, source_path) => write!(f, "{} is no longer the tracked link to {}", target_path, source_path),
+            UnlinkError::UnlinkIOError(ioerror) => write!(f, "Encountered an io error while unlinking: {}", ioerror),
+        }
+    }
+}
+
+impl From<io::Error> for UnlinkError {
+    fn from(value: io::Error) -> Self {
+        UnlinkError::UnlinkIOError(value)
+    }
+}
+
+impl From<UnlinkError> for io::Error {
+    fn from(value: UnlinkError) -> Self {
+        match value {
+            UnlinkError::UnlinkIOError(ioerror) => ioerror,
+            not_tracked => io::Error::other(not_tracked),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize, Default)]
 enum LinkType {
     #[default]
+    #[value(alias = "soft")]
     Softlink,
+    #[value(alias = "hard")]
     Hardlink
 }
 
@@ -157,26 +1898,108 @@ impl Display for FileType {
     }
 }
 
+/// State of a tracked link on disk, compared to its record.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum LinkState {
+    /// The filesystem matches the record
+    Ok,
+    /// Recorded as present, but the target is missing
+    Missing,
+    /// The source no longer exists
+    Dangling,
+    /// Recorded as present, but the target is something other than this link
+    Hijacked,
+    /// Recorded as present, but the target was replaced by a regular file with the source's content
+    Delinked,
+    /// Recorded as absent, but the link is present
+    Unexpected,
+}
+
+impl LinkState {
+    /// One-line explanation of the state
+    fn describe(&self) -> &'static str {
+        match self {
+            LinkState::Ok => "link matches its record",
+            LinkState::Missing => "target is missing",
+            LinkState::Dangling => "source no longer exists",
+            LinkState::Hijacked => "target is not the tracked link",
+            LinkState::Delinked => "target is a copy of the source instead of a link",
+            LinkState::Unexpected => "link is present although recorded as disabled",
+        }
+    }
+}
+
+impl Display for LinkState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LinkState::Ok => write!(f, "OK"),
+            LinkState::Missing => write!(f, "MISSING"),
+            LinkState::Dangling => write!(f, "DANGLING"),
+            LinkState::Hijacked => write!(f, "HIJACKED"),
+            LinkState::Delinked => write!(f, "DELINKED"),
+            LinkState::Unexpected => write!(f, "UNEXPECTED"),
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
 /// A soft/hard link wrapper, that remembers what it is.
 /// Can be not present in the filesystem.
 struct QuickLink {
+    #[serde(with = "path_bytes")]
     source: PathBuf,
+    #[serde(with = "path_bytes")]
     target: PathBuf,
     exists: bool,
     linktype: LinkType,
+    /// Command-line arguments the link was created with, if recording was requested.
+    #[serde(default)]
+    origin_args: Option<Vec<String>>,
+    /// Recorded before its source existed, and not linked yet.
+    #[serde(default)]
+    deferred: bool,
+    /// OS names and `host:<hostname>` conditions restricting where the link is created. Empty means everywhere.
+    #[serde(default)]
+    only_on: Vec<String>,
+    /// Id of the invocation that created the link, shared by all links it created.
+    #[serde(default)]
+    session_id: Option<String>,
+    /// Softlink pointing to the source by a path relative to the target's directory.
+    #[serde(default)]
+    relative: bool,
+    /// Softlink carrying its source's access and modification times. Hardlinks share them with the source anyway.
+    #[serde(default)]
+    preserve: bool,
+    /// Where the file that was at the target before the link was created has been moved to.
+    #[serde(default, with = "path_bytes::option")]
+    backup: Option<PathBuf>,
+    /// Names of the groups the link belongs to.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Unique name to address the link by instead of its target path.
+    #[serde(default)]
+    name: Option<String>,
+    /// Free-text note on why the link exists.
+    #[serde(default)]
+    note: Option<String>,
+    /// When the link was first recorded, in unix seconds.
+    #[serde(default)]
+    created_at: Option<u64>,
+    /// When the link was last linked or unlinked, in unix seconds.
+    #[serde(default)]
+    updated_at: Option<u64>,
+    /// Directories a recursive Create made for the link, innermost first, removed along with it once they are empty.
+    #[serde(default, with = "path_bytes::vec")]
+    created_dirs: Vec<PathBuf>,
 }
 
 impl QuickLink {
     /// Create a new QuickLink object, without linking it.
     /// Supports importing an existing softlink, provided the target file is already one pointing exactly to the source.
     pub fn new(source: &Path, target: &Path, linktype: LinkType) -> Result<QuickLink, QuickLinkCreationError> {
-        let abs_source = absolute_path(source);
-        let abs_target = absolute_path(target);
-        if !abs_source.exists() {
-            return Err(QuickLinkCreationError::SourceDoesNotExist(abs_source.to_string_lossy().into_owned()));
-        }
+        let abs_source = absolute_path(source, &env::current_dir()?);
+        let abs_target = absolute_path(target, &env::current_dir()?);
+        QuickLink::check_source(&abs_source, linktype)?;
         let mut exists = false;
         if abs_target.exists() {
             exists = true;
@@ -186,16 +2009,115 @@ impl QuickLink {
                 }
             }
             else if linktype == LinkType::Hardlink {
-                // There might be a way to do it later, for now - always abort, as if it was just a file.
+                // A file sharing the source's inode already is the hardlink, so it is imported
+                if !same_inode(&abs_source, &abs_target) {
+                    return Err(QuickLinkCreationError::TargetExists(abs_source.to_string_lossy().into_owned(), abs_target.to_string_lossy().into_owned()));
+                }
             }
             else {
                 return Err(QuickLinkCreationError::TargetExists(abs_source.to_string_lossy().into_owned(), abs_target.to_string_lossy().into_owned()));
             }
         }
-        if abs_target.is_dir() && (linktype == LinkType::Hardlink) {
-            return Err(QuickLinkCreationError::UnavailableLinkType(abs_source.to_string_lossy().into_owned(), linktype, FileType::Directory));
+        Ok(QuickLink { source: abs_source, target: abs_target, exists, linktype, created_at: Some(unix_now()), updated_at: Some(unix_now()), ..Default::default() })
+    }
+
+    /// Check that an absolute `source` exists and can be linked as `linktype`.
+    fn check_source(source: &Path, linktype: LinkType) -> Result<(), QuickLinkCreationError> {
+        // Hardlinking a symlink links the symlink itself, so it only has to exist, not resolve
+        let source_present = match linktype {
+            LinkType::Softlink => source.exists(),
+            LinkType::Hardlink => source.symlink_metadata().is_ok(),
+        };
+        if !source_present {
+            return Err(QuickLinkCreationError::SourceDoesNotExist(source.to_string_lossy().into_owned()));
+        }
+        if source.symlink_metadata().is_ok_and(|meta| meta.is_dir()) && (linktype == LinkType::Hardlink) {
+            return Err(QuickLinkCreationError::UnavailableLinkType(source.to_string_lossy().into_owned(), linktype, FileType::Directory));
+        }
+        Ok(())
+    }
+
+    /// Create a QuickLink object for an existing symlink at `target`, taking its source from the symlink.
+    pub fn from_symlink(target: &Path) -> Result<QuickLink, QuickLinkCreationError> {
+        let abs_target = absolute_path(target, &env::current_dir()?);
+        if !abs_target.is_symlink() {
+            return Err(QuickLinkCreationError::NotASymlink(abs_target.to_string_lossy().into_owned()));
+        }
+        let destination = read_link(&abs_target)?;
+        let source = absolute_path(&destination, abs_target.parent().unwrap_or(Path::new("/")));
+        Ok(QuickLink { source, target: abs_target, exists: true, linktype: LinkType::Softlink, relative: destination.is_relative(),
+            created_at: Some(unix_now()), updated_at: Some(unix_now()), ..Default::default() })
+    }
+
+    /// Create a new QuickLink object for a source that does not exist yet.
+    /// It is recorded as deferred, and not linked until the source appears.
+    pub fn new_deferred(source: &Path, target: &Path, linktype: LinkType) -> Result<QuickLink, QuickLinkCreationError> {
+        let abs_source = absolute_path(source, &env::current_dir()?);
+        let abs_target = absolute_path(target, &env::current_dir()?);
+        if abs_target.symlink_metadata().is_ok() {
+            return Err(QuickLinkCreationError::TargetExists(abs_source.to_string_lossy().into_owned(), abs_target.to_string_lossy().into_owned()));
+        }
+        Ok(QuickLink { source: abs_source, target: abs_target, exists: false, linktype, deferred: true, created_at: Some(unix_now()), updated_at: Some(unix_now()), ..Default::default() })
+    }
+
+    /// Whether the target on disk currently is this link - a symlink to the source, or a hardlink sharing its inode.
+    pub fn is_in_place(&self) -> bool {
+        match self.linktype {
+            LinkType::Softlink => read_link(&self.target).is_ok_and(|destination| destination == symlink_destination(&self.source, &self.target, self.relative)),
+            LinkType::Hardlink => same_inode(&self.source, &self.target),
+        }
+    }
+
+    /// Whether the link's `only_on` conditions match this machine.
+    /// Conditions of the same kind are alternatives, while OS and host conditions must both match.
+    pub fn applies_here(&self) -> bool {
+        let (hosts, systems): (Vec<&String>, Vec<&String>) = self.only_on.iter().partition(|condition| condition.starts_with("host:"));
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+            .unwrap_or_default();
+        (systems.is_empty() || systems.iter().any(|system| *system == env::consts::OS))
+            && (hosts.is_empty() || hosts.iter().any(|host| host["host:".len()..] == *hostname.trim()))
+    }
+
+    /// Recorded and live values of the source, type and presence of this link, as (field, recorded, live) rows.
+    pub fn inspect(&self) -> Vec<(&'static str, String, String)> {
+        let target_meta = self.target.symlink_metadata().ok();
+        let shares_inode = || same_inode(&self.source, &self.target);
+        let (live_source, live_type) = match &target_meta {
+            None => ("(missing)".to_string(), "(missing)".to_string()),
+            Some(meta) if meta.is_symlink() && self.is_in_place() => (self.source.display().to_string(), LinkType::Softlink.to_string()),
+            Some(meta) if meta.is_symlink() => {
+                let destination = read_link(&self.target).map(|destination| absolute_path(&destination, self.target.parent().unwrap_or(Path::new("/"))));
+                (destination.map_or("(unreadable)".to_string(), |d| d.display().to_string()), LinkType::Softlink.to_string())
+            },
+            Some(meta) if meta.is_dir() => ("(none)".to_string(), FileType::Directory.to_string()),
+            Some(_) if shares_inode() => (self.source.display().to_string(), LinkType::Hardlink.to_string()),
+            Some(_) => ("(none)".to_string(), FileType::File.to_string()),
+        };
+        vec![
+            ("source", self.source.display().to_string(), live_source),
+            ("type", self.linktype.to_string(), live_type),
+            ("exists", self.exists.to_string(), self.is_in_place().to_string()),
+        ]
+    }
+
+    /// Compare the filesystem against this record. Only reads metadata.
+    pub fn state(&self) -> LinkState {
+        if self.source.symlink_metadata().is_err() {
+            if self.deferred { LinkState::Ok } else { LinkState::Dangling }
+        } else if self.exists && self.target.symlink_metadata().is_err() {
+            LinkState::Missing
+        } else if self.exists && !self.is_in_place() {
+            if self.target.is_file() && !self.target.is_symlink() && same_content(&self.source, &self.target) {
+                LinkState::Delinked
+            } else {
+                LinkState::Hijacked
+            }
+        } else if !self.exists && self.is_in_place() {
+            LinkState::Unexpected
+        } else {
+            LinkState::Ok
         }
-        Ok(QuickLink { source: abs_source, target: abs_target, exists, linktype })
     }
 
     /// Create a new QuickLink object, without linking it.
@@ -224,11 +2146,16 @@ impl QuickLink {
             LinkType::Hardlink => self.hardlink(),
         }?;
         self.exists = true;
+        self.deferred = false;
+        self.updated_at = Some(unix_now());
         Ok(())
     }
 
     fn softlink(&self) -> std::io::Result<()>{
-        fs::symlink(&self.source, &self.target)?;
+        platform::symlink(&symlink_destination(&self.source, &self.target, self.relative), &self.target)?;
+        if self.preserve {
+            platform::set_symlink_times(&self.target, &std::fs::metadata(&self.source)?)?;
+        }
         Ok(())
     }
 
@@ -237,16 +2164,242 @@ impl QuickLink {
         Ok(())
     }
 
-    pub fn unlink(&mut self) -> std::io::Result<()> {
+    /// Delete the link at the target. Refuses a target that no longer is the tracked link, e.g. a file that replaced it.
+    pub fn unlink(&mut self) -> Result<(), UnlinkError> {
+        self.ensure_tracked_link()?;
+        self.unlink_forced()?;
+        Ok(())
+    }
+
+    /// Delete whatever file is at the target, without checking that it is the tracked link.
+    pub fn unlink_forced(&mut self) -> std::io::Result<()> {
         std::fs::remove_file(&self.target)?; // links to directories are still just files
         self.exists = false;
+        self.updated_at = Some(unix_now());
+        Ok(())
+    }
+
+    /// Check that the target, if there is one, still is this link: a symlink to the source, or a hardlink sharing its inode.
+    pub fn ensure_tracked_link(&self) -> Result<(), UnlinkError> {
+        if self.target.symlink_metadata().is_ok() && !self.is_in_place() {
+            return Err(UnlinkError::NotTheTrackedLink(self.target.to_string_lossy().into_owned(), self.source.to_string_lossy().into_owned()));
+        }
+        Ok(())
+    }
+
+    /// Move the link to `new_target`, which must not exist, refusing a target that is not the tracked link.
+    /// A link that is not on disk is only moved in the record. The old link is restored on failure.
+    pub fn relocate(&mut self, new_target: &Path) -> std::io::Result<()> {
+        if new_target.symlink_metadata().is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", new_target.display())));
+        }
+        let linked = self.exists && self.target.symlink_metadata().is_ok();
+        if linked && !self.is_in_place() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not the tracked link, refusing to move it", self.target.display())));
+        }
+        if linked {
+            self.unlink()?;
+        }
+        let old_target = std::mem::replace(&mut self.target, new_target.to_path_buf());
+        if linked && let Err(e) = self.link() {
+            self.target = old_target;
+            self.link()?;
+            return Err(e);
+        }
+        // A link that was not on disk is only moved in the record
+        self.exists = linked;
+        Ok(())
+    }
+
+    /// Point the link to `new_source`, relinking it if it is present. The old link is restored on failure.
+    pub fn retarget(&mut self, new_source: &Path) -> std::io::Result<()> {
+        let linked = self.exists && self.target.symlink_metadata().is_ok();
+        if linked && !self.is_in_place() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not the tracked link, refusing to replace it", self.target.display())));
+        }
+        if linked {
+            self.unlink()?;
+        }
+        let old_source = std::mem::replace(&mut self.source, new_source.to_path_buf());
+        if linked && let Err(e) = self.link() {
+            self.source = old_source;
+            self.link()?;
+            return Err(e);
+        }
         Ok(())
     }
+
+    /// Move the backup made when the link was created back to the target, which must be free.
+    pub fn restore_backup(&mut self) -> std::io::Result<()> {
+        if let Some(backup) = &self.backup {
+            if self.target.symlink_metadata().is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists, not restoring {}", self.target.display(), backup.display())));
+            }
+            std::fs::rename(backup, &self.target)?;
+            self.backup = None;
+        }
+        Ok(())
+    }
+
+    /// Swap the roles of source and target: the source file moves to the target location,
+    /// and the link is recreated at the old source location. The filesystem is rolled back on failure.
+    pub fn swap(&mut self) -> std::io::Result<()> {
+        let linked = self.target.symlink_metadata().is_ok();
+        if linked && !self.is_in_place() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not the tracked link, refusing to replace it", self.target.display())));
+        }
+        let softlinked = linked && self.linktype == LinkType::Softlink;
+        if softlinked {
+            std::fs::remove_file(&self.target)?;
+        }
+        // A present hardlink already is the same file at both paths, so nothing has to move.
+        if (softlinked || !linked)
+            && let Err(e) = std::fs::rename(&self.source, &self.target) {
+            if softlinked {
+                self.softlink()?;
+            }
+            return Err(e);
+        }
+        if softlinked && let Err(e) = platform::symlink(&symlink_destination(&self.target, &self.source, self.relative), &self.source) {
+            std::fs::rename(&self.target, &self.source)?;
+            self.softlink()?;
+            return Err(e);
+        }
+        std::mem::swap(&mut self.source, &mut self.target);
+        self.exists = linked;
+        Ok(())
+    }
+}
+
+impl QuickLink {
+    /// Format like Display, with paths inside `base` shown relative to it
+    pub fn display_relative(&self, base: &Path) -> String {
+        format!("{} -> {}{}", relative_path(&self.source, base).display(), relative_path(&self.target, base).display(), self.display_details())
+    }
+
+    /// Everything Display shows after the paths
+    fn display_details(&self) -> String {
+        let mut details = format!(" , e: {}, t: {}", self.exists, self.linktype);
+        if let Some(name) = &self.name {
+            details.push_str(&format!(", name: {}", name));
+        }
+        if self.deferred {
+            details.push_str(", deferred");
+        }
+        if !self.only_on.is_empty() {
+            details.push_str(&format!(", only on: {}", self.only_on.join(", ")));
+        }
+        if !self.tags.is_empty() {
+            details.push_str(&format!(", tags: {}", self.tags.join(", ")));
+        }
+        if let Some(note) = &self.note {
+            details.push_str(&format!(", note: {}", note));
+        }
+        if let Some(created_at) = self.created_at {
+            details.push_str(&format!(", age: {}", format_age(unix_now().saturating_sub(created_at))));
+        }
+        details
+    }
 }
 
 impl Display for QuickLink {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "{} -> {} , e: {}, t: {}", self.source.to_str().unwrap(), self.target.to_str().unwrap(), self.exists, self.linktype)
+        write!(f, "{} -> {}{}", self.source.display(), self.target.display(), self.display_details())
     }
 }
 
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An empty directory under the system temp dir, unique to this test process and `name`
+    pub(crate) fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fslinkmanager-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A LinkStorage in a new database in `dir`
+    pub(crate) fn scratch_storage(dir: &Path) -> LinkStorage {
+        LinkStorage::create(dir, false).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn softlink(source: &Path, target: &Path) -> QuickLink {
+        QuickLink { source: source.to_path_buf(), target: target.to_path_buf(), linktype: LinkType::Softlink, ..Default::default() }
+    }
+
+    #[test]
+    fn link_cycle_of_two_links() {
+        let dir = scratch_dir("cycle-2");
+        let db = scratch_storage(&dir);
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        db.save_quicklink(&softlink(&a, &b)).unwrap();
+        assert_eq!(find_link_cycle(&db, &b, &a), Some(vec![b.clone(), a.clone()]));
+        assert_eq!(find_link_cycle(&db, &b, &dir.join("c")), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn link_cycle_of_three_links() {
+        let dir = scratch_dir("cycle-3");
+        let db = scratch_storage(&dir);
+        let (a, b, c) = (dir.join("a"), dir.join("b"), dir.join("c"));
+        db.save_quicklink(&softlink(&a, &b)).unwrap();
+        db.save_quicklink(&softlink(&b, &c)).unwrap();
+        assert_eq!(find_link_cycle(&db, &c, &a), Some(vec![c.clone(), b.clone(), a.clone()]));
+        // Paths inside a linked directory resolve through it too
+        assert_eq!(find_link_cycle(&db, &c.join("x"), &a), Some(vec![c.join("x"), b.join("x"), a.join("x")]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn existing_hardlink_is_imported() {
+        let dir = scratch_dir("hardlink-import");
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        std::fs::write(&source, "content").unwrap();
+        std::fs::hard_link(&source, &target).unwrap();
+        let imported = QuickLink::new(&source, &target, LinkType::Hardlink);
+        assert!(matches!(imported, Ok(QuickLink { exists: true, .. })));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_file_at_hardlink_target_is_refused() {
+        let dir = scratch_dir("hardlink-other");
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        std::fs::write(&source, "content").unwrap();
+        std::fs::write(&target, "content").unwrap();
+        let refused = QuickLink::new(&source, &target, LinkType::Hardlink);
+        assert!(matches!(refused, Err(QuickLinkCreationError::TargetExists(..))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn normalize_path_drops_current_dir() {
+        assert_eq!(normalize_path(Path::new("/a/./b/.")), PathBuf::from("/a/b"));
+        assert_eq!(normalize_path(Path::new("./a")), PathBuf::from("a"));
+        assert_eq!(normalize_path(Path::new(".")), PathBuf::new());
+    }
+
+    #[test]
+    fn normalize_path_drops_trailing_slash() {
+        assert_eq!(normalize_path(Path::new("/a/b/")), PathBuf::from("/a/b"));
+        assert_eq!(normalize_path(Path::new("/a/b/..//")), PathBuf::from("/a"));
+    }
+
+    #[test]
+    fn normalize_path_resolves_several_parent_dirs() {
+        assert_eq!(normalize_path(Path::new("/a/b/c/../../d")), PathBuf::from("/a/d"));
+        assert_eq!(normalize_path(Path::new("a/b/../../c")), PathBuf::from("c"));
+        assert_eq!(normalize_path(Path::new("a/../../b")), PathBuf::from("../b"));
+        assert_eq!(normalize_path(Path::new("../../a")), PathBuf::from("../../a"));
+    }
+
+    #[test]
+    fn normalize_path_stops_parent_dirs_at_root() {
+        assert_eq!(normalize_path(Path::new("/..")), PathBuf::from("/"));
+        assert_eq!(normalize_path(Path::new("/../a")), PathBuf::from("/a"));
+        assert_eq!(normalize_path(Path::new("/a/../../../b")), PathBuf::from("/b"));
+    }
+}
@@ -0,0 +1,140 @@
+//! Platform-specific filesystem calls, so the rest of the crate does not depend on `std::os::*` for linking
+
+use std::{fs::Metadata, io, path::{Path, PathBuf}};
+
+/// Windows error code returned when creating a symlink without Developer Mode or elevation
+#[cfg(windows)]
+const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+/// Windows error code returned when moving or hardlinking a file to another volume
+#[cfg(windows)]
+const ERROR_NOT_SAME_DEVICE: i32 = 17;
+
+/// Create a symlink at `link` pointing to the file `original`
+#[cfg(unix)]
+pub fn symlink_file(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+/// Create a symlink at `link` pointing to the directory `original`
+#[cfg(unix)]
+pub fn symlink_dir(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+/// Create a symlink at `link` pointing to the file `original`
+#[cfg(windows)]
+pub fn symlink_file(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Create a symlink at `link` pointing to the directory `original`
+#[cfg(windows)]
+pub fn symlink_dir(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_dir(original, link)
+}
+
+/// Create a symlink at `link` pointing to `original`, as a directory symlink if `original` is a directory.
+/// A relative `original` is taken relative to the link's directory, as the symlink will resolve it.
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    let resolved = link.parent().map_or_else(|| original.to_path_buf(), |directory| directory.join(original));
+    if resolved.is_dir() {
+        symlink_dir(original, link)
+    } else {
+        symlink_file(original, link)
+    }
+}
+
+/// Give the symlink at `link` itself the access and modification times in `metadata`
+#[cfg(unix)]
+pub fn set_symlink_times(link: &Path, metadata: &std::fs::Metadata) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    use nix::sys::time::TimeVal;
+    let accessed = TimeVal::new(metadata.atime(), metadata.atime_nsec() / 1000);
+    let modified = TimeVal::new(metadata.mtime(), metadata.mtime_nsec() / 1000);
+    nix::sys::stat::lutimes(link, &accessed, &modified)?;
+    Ok(())
+}
+
+/// Give the symlink at `link` itself the access and modification times in `metadata`.
+/// Not supported here, so symlinks keep the time they were created at.
+#[cfg(windows)]
+pub fn set_symlink_times(_link: &Path, _metadata: &std::fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `error` means the process may not create symlinks at all
+#[cfg(windows)]
+pub fn is_symlink_privilege_error(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD)
+}
+
+/// Whether `error` means the process may not create symlinks at all
+#[cfg(not(windows))]
+pub fn is_symlink_privilege_error(_error: &io::Error) -> bool {
+    false
+}
+
+/// Whether `error` means a hardlink or rename was refused because it crosses filesystems
+#[cfg(unix)]
+pub fn is_cross_device_error(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32)
+}
+
+/// Whether `error` means a hardlink or rename was refused because it crosses filesystems
+#[cfg(windows)]
+pub fn is_cross_device_error(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE)
+}
+
+/// The (device, inode) pair identifying the file `metadata` belongs to, shared by all its hardlinks
+#[cfg(unix)]
+pub fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// The (device, inode) pair identifying the file `metadata` belongs to. Not available here without opening the file
+#[cfg(windows)]
+pub fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The permission bits of `metadata` in octal, as `ls -l` shows them
+#[cfg(unix)]
+pub fn file_mode(metadata: &Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    Some(format!("{:o}", metadata.mode()))
+}
+
+/// The permission bits of `metadata` in octal. Windows has none
+#[cfg(windows)]
+pub fn file_mode(_metadata: &Metadata) -> Option<String> {
+    None
+}
+
+/// The id, total and available inode counts of the filesystem `dir` is on, if it reports them
+#[cfg(unix)]
+pub fn inode_counts(dir: &Path) -> Option<(u64, u64, u64)> {
+    let stats = nix::sys::statvfs::statvfs(dir).ok()?;
+    Some((stats.filesystem_id() as u64, stats.files() as u64, stats.files_available() as u64))
+}
+
+/// The id, total and available inode counts of the filesystem `dir` is on. Windows does not limit inodes
+#[cfg(windows)]
+pub fn inode_counts(_dir: &Path) -> Option<(u64, u64, u64)> {
+    None
+}
+
+/// A path from the raw bytes `path_bytes` stored it as
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+/// A path from the raw bytes `path_bytes` stored it as. Bytes that are not UTF-8 are replaced
+#[cfg(windows)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
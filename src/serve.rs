@@ -0,0 +1,66 @@
+use std::{fs::remove_file, io::{self, BufRead, BufReader, Write}, os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}}, path::{Path, PathBuf}};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{absolute_path, link_json};
+use crate::database::LinkStorage;
+
+/// A single request, one JSON object per line, e.g. `{"cmd":"which","path":"/home/me/.vimrc"}`
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Request {
+    /// All tracked links
+    List,
+    /// The tracked link at a target path
+    Which { path: PathBuf },
+    /// Tracked links whose source is, or lies under, a path
+    Dependents { path: PathBuf },
+}
+
+/// Listen on a Unix domain socket and answer line-delimited JSON requests until killed.
+/// Connections are handled one at a time; relative paths in requests are resolved against `base`.
+pub fn serve(db: &LinkStorage, socket: &Path, base: &Path) -> io::Result<()> {
+    if socket.symlink_metadata().is_ok_and(|meta| meta.file_type().is_socket()) {
+        remove_file(socket)?; // Left behind by a previous server
+    }
+    let listener = UnixListener::bind(socket)?;
+    println!("Listening on {}", socket.display());
+    for stream in listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| handle_connection(db, stream, base)) {
+            eprintln!("Connection error: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection(db: &LinkStorage, stream: UnixStream, base: &Path) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => respond(db, request, base),
+            Err(e) => json!({ "ok": false, "error": format!("invalid request: {}", e) }),
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn respond(db: &LinkStorage, request: Request, base: &Path) -> Value {
+    db.refresh(); // Other processes may have changed the database since the last request
+    match request {
+        Request::List => json!({ "ok": true, "links": db.get_all().iter().map(|link| link_json(db, link)).collect::<Vec<_>>() }),
+        Request::Which { path } => match db.find_by_target(&absolute_path(&path, base)) {
+            Some(link) => json!({ "ok": true, "link": link_json(db, &link) }),
+            None => json!({ "ok": false, "error": format!("no tracked link found for target: {}", path.display()) }),
+        },
+        Request::Dependents { path } => {
+            let dependents = db.find_by_source_prefix(&absolute_path(&path, base));
+            json!({ "ok": true, "links": dependents.iter().map(|link| link_json(db, link)).collect::<Vec<_>>() })
+        },
+    }
+}
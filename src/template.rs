@@ -0,0 +1,77 @@
+use crate::QuickLink;
+use crate::database::LinkStorage;
+
+/// Placeholders a List template can use
+pub const PLACEHOLDERS: [&str; 11] = ["source", "target", "type", "exists", "state", "id", "session", "created", "updated", "tags", "note"];
+
+/// A parsed List output template, such as `{source} => {target} [{type}] {state}`.
+/// `{{` and `}}` stand for literal braces.
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+}
+
+impl Template {
+    /// Parse a template, failing on unknown placeholders and unbalanced braces
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed placeholder {{{}", name)),
+                        }
+                    }
+                    if !PLACEHOLDERS.contains(&name.as_str()) {
+                        return Err(format!("unknown placeholder {{{}}}, expected one of: {}", name, PLACEHOLDERS.join(", ")));
+                    }
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    segments.push(Segment::Placeholder(name));
+                },
+                '}' => return Err("unmatched } in template, use }} for a literal brace".to_string()),
+                c => literal.push(c),
+            }
+        }
+        segments.push(Segment::Literal(literal));
+        Ok(Template { segments })
+    }
+
+    /// Fill in the placeholders for one link
+    pub fn render(&self, link: &QuickLink, db: &LinkStorage) -> String {
+        self.segments.iter().map(|segment| match segment {
+            Segment::Literal(text) => text.clone(),
+            Segment::Placeholder(name) => match name.as_str() {
+                "source" => link.source.display().to_string(),
+                "target" => link.target.display().to_string(),
+                "type" => link.linktype.to_string(),
+                "exists" => link.exists.to_string(),
+                "state" => link.state().to_string(),
+                "id" => db.record_id(link),
+                "session" => link.session_id.clone().unwrap_or_default(),
+                "created" => link.created_at.map(|time| time.to_string()).unwrap_or_default(),
+                "updated" => link.updated_at.map(|time| time.to_string()).unwrap_or_default(),
+                "tags" => link.tags.join(","),
+                "note" => link.note.clone().unwrap_or_default(),
+                _ => unreachable!("placeholders are validated when parsing"),
+            },
+        }).collect()
+    }
+}
@@ -0,0 +1,74 @@
+use std::{collections::HashSet, io, path::{Path, PathBuf}, sync::mpsc, time::Duration};
+use notify::{Event, RecursiveMode, Watcher};
+
+use crate::{LinkType, QuickLink};
+use crate::database::LinkStorage;
+
+/// How long events must stop arriving before the links are checked, so that an editor's save
+/// (write a temporary file, then rename it over the original) is handled once, after it is complete
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watch the directories of the sources and targets of the links tracked when it starts, repairing the links
+/// affected by each burst of changes and creating deferred ones once their source appears. Runs until the process is killed.
+pub fn watch(db: &LinkStorage) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(sender).map_err(io::Error::other)?;
+    let links: Vec<QuickLink> = db.get_all().into_iter().filter(|link| (link.exists || link.deferred) && link.applies_here()).collect();
+    let directories: HashSet<&Path> = links.iter().flat_map(|link| [link.source.parent(), link.target.parent()]).flatten().collect();
+    for dir in &directories {
+        if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            eprintln!("Cannot watch {}: {}", dir.display(), e);
+        }
+    }
+    println!("Watching {} links in {} directories", links.len(), directories.len());
+    while let Ok(event) = receiver.recv() {
+        let mut changed = HashSet::new();
+        collect_paths(event, &mut changed);
+        while let Ok(event) = receiver.recv_timeout(DEBOUNCE) {
+            collect_paths(event, &mut changed);
+        }
+        db.refresh();
+        for mut link in db.get_all().into_iter().filter(|link| (link.exists || link.deferred) && link.applies_here()) {
+            let source_changed = changed.contains(&link.source);
+            if (source_changed || changed.contains(&link.target))
+                && let Err(e) = repair(db, &mut link, source_changed) {
+                eprintln!("Error repairing {}: {}", link.target.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn collect_paths(event: notify::Result<Event>, changed: &mut HashSet<PathBuf>) {
+    match event {
+        Ok(event) => changed.extend(event.paths),
+        Err(e) => eprintln!("Watch error: {}", e),
+    }
+}
+
+/// Relink `link` if its target is missing, or if it is a hardlink left pointing to the old file after its source was replaced.
+/// Anything else at the target is left alone.
+fn repair(db: &LinkStorage, link: &mut QuickLink, source_changed: bool) -> io::Result<()> {
+    if link.source.symlink_metadata().is_err() {
+        println!("Source missing, waiting for it to reappear: {}", link);
+        return Ok(());
+    }
+    if link.is_in_place() {
+        return Ok(());
+    }
+    match link.target.symlink_metadata() {
+        Err(_) => {},
+        Ok(meta) if source_changed && link.linktype == LinkType::Hardlink && meta.is_file() => std::fs::remove_file(&link.target)?,
+        Ok(_) => {
+            eprintln!("Not repairing, the target is not the tracked link: {}", link);
+            return Ok(());
+        }
+    }
+    if let Some(parent) = link.target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    link.link()?;
+    db.save_quicklink(link)?;
+    println!("Link repaired: {}", link);
+    Ok(())
+}